@@ -43,6 +43,47 @@ impl Location {
                 .fetch_optional(pool).await
         }
     }
+
+    /// Like [`Self::fetch_optional`], but falls back to the other language if the location is missing in the requested one
+    ///
+    /// Showing the location in the wrong language is better than not showing it at all.
+    /// The gap in the data is logged, so that it can be fixed.
+    #[tracing::instrument(skip(pool))]
+    pub async fn fetch_optional_in_any_language(
+        pool: &PgPool,
+        id: &str,
+        should_use_english: bool,
+    ) -> sqlx::Result<Option<Self>> {
+        if let Some(location) = Self::fetch_optional(pool, id, should_use_english).await? {
+            return Ok(Some(location));
+        }
+        let location = Self::fetch_optional(pool, id, !should_use_english).await?;
+        if location.is_some() {
+            let missing = if should_use_english { "en" } else { "de" };
+            tracing::warn!(
+                id,
+                missing,
+                "location is missing in one language, falling back to the other one"
+            );
+        }
+        Ok(location)
+    }
+
+    /// Key of a random location, which can be shown on a map.
+    ///
+    /// Virtual rooms are skipped, as they have no place of their own
+    #[tracing::instrument(skip(pool))]
+    pub async fn fetch_random_key(pool: &PgPool) -> sqlx::Result<Option<String>> {
+        sqlx::query_scalar(
+            r#"SELECT key
+            FROM de
+            WHERE type <> 'virtual_room' AND lat BETWEEN -85 AND 85 AND lon BETWEEN -180 AND 180
+            ORDER BY random()
+            LIMIT 1"#,
+        )
+        .fetch_optional(pool)
+        .await
+    }
 }
 
 #[allow(dead_code)] // used for testing out the repo pattern
@@ -68,3 +109,161 @@ impl LocationKeyAlias {
         .await
     }
 }
+
+/// Outline of a location, e.g. of a building
+#[derive(Debug, Clone, PartialEq)]
+pub struct Footprint {
+    /// exterior ring of the polygon as `(lat, lon)` points
+    pub outline: Vec<(f64, f64)>,
+}
+impl Footprint {
+    /// Fetches the GeoJSON `Polygon` stored in `data.geometry`.
+    ///
+    /// Other geometries and malformed ones are ignored, as the footprint is only used for decoration
+    #[tracing::instrument(skip(pool))]
+    pub async fn fetch_optional(pool: &PgPool, id: &str) -> sqlx::Result<Option<Self>> {
+        let geometry = fetch_geometry(pool, id).await?;
+        Ok(geometry.and_then(Self::from_geojson))
+    }
+
+    fn from_geojson(geometry: serde_json::Value) -> Option<Self> {
+        #[derive(serde::Deserialize)]
+        struct GeoJsonPolygon {
+            coordinates: Vec<Vec<[f64; 2]>>,
+        }
+        if geometry_type(&geometry) != Some("Polygon") {
+            return None;
+        }
+        let polygon = match serde_json::from_value::<GeoJsonPolygon>(geometry) {
+            Ok(polygon) => polygon,
+            Err(e) => {
+                tracing::warn!(error = ?e, "could not parse the geometry of the location");
+                return None;
+            }
+        };
+        // geojson is lon/lat ordered
+        let outline = polygon
+            .coordinates
+            .into_iter()
+            .next()?
+            .into_iter()
+            .map(|[lon, lat]| (lat, lon))
+            .collect::<Vec<_>>();
+        // the ring is closed => a triangle has 4 points
+        if outline.len() < 4 {
+            return None;
+        }
+        Some(Self { outline })
+    }
+}
+
+/// Places of a location which consists of multiple ones, e.g. of a set of rooms
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pins {
+    /// `(lat, lon)` of each place
+    pub coordinates: Vec<(f64, f64)>,
+}
+impl Pins {
+    /// Fetches the GeoJSON `MultiPoint` stored in `data.geometry`.
+    ///
+    /// A single point is no set of places => [`None`] is returned, like for other geometries and malformed ones
+    #[tracing::instrument(skip(pool))]
+    pub async fn fetch_optional(pool: &PgPool, id: &str) -> sqlx::Result<Option<Self>> {
+        let geometry = fetch_geometry(pool, id).await?;
+        Ok(geometry.and_then(Self::from_geojson))
+    }
+
+    fn from_geojson(geometry: serde_json::Value) -> Option<Self> {
+        #[derive(serde::Deserialize)]
+        struct GeoJsonMultiPoint {
+            coordinates: Vec<[f64; 2]>,
+        }
+        if geometry_type(&geometry) != Some("MultiPoint") {
+            return None;
+        }
+        let points = match serde_json::from_value::<GeoJsonMultiPoint>(geometry) {
+            Ok(points) => points,
+            Err(e) => {
+                tracing::warn!(error = ?e, "could not parse the geometry of the location");
+                return None;
+            }
+        };
+        // geojson is lon/lat ordered
+        let coordinates = points
+            .coordinates
+            .into_iter()
+            .map(|[lon, lat]| (lat, lon))
+            .collect::<Vec<_>>();
+        (coordinates.len() > 1).then_some(Self { coordinates })
+    }
+}
+
+/// The GeoJSON geometry stored in `data.geometry`, if there is one
+async fn fetch_geometry(pool: &PgPool, id: &str) -> sqlx::Result<Option<serde_json::Value>> {
+    let geometry: Option<Option<serde_json::Value>> =
+        sqlx::query_scalar("SELECT data->'geometry' FROM de WHERE key = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+    Ok(geometry.flatten())
+}
+
+fn geometry_type(geometry: &serde_json::Value) -> Option<&str> {
+    geometry.get("type").and_then(serde_json::Value::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn footprint_from_geojson() {
+        let square = serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [[[11.0, 48.0], [11.1, 48.0], [11.1, 48.1], [11.0, 48.1], [11.0, 48.0]]]
+        });
+        assert_eq!(
+            Footprint::from_geojson(square),
+            Some(Footprint {
+                outline: vec![
+                    (48.0, 11.0),
+                    (48.0, 11.1),
+                    (48.1, 11.1),
+                    (48.1, 11.0),
+                    (48.0, 11.0)
+                ]
+            })
+        );
+        let point = serde_json::json!({"type": "Point", "coordinates": [11.0, 48.0]});
+        assert_eq!(Footprint::from_geojson(point), None);
+        let degenerate =
+            serde_json::json!({"type": "Polygon", "coordinates": [[[11.0, 48.0], [11.0, 48.0]]]});
+        assert_eq!(Footprint::from_geojson(degenerate), None);
+    }
+
+    #[test]
+    fn pins_from_geojson() {
+        let rooms = serde_json::json!({
+            "type": "MultiPoint",
+            "coordinates": [[11.0, 48.0], [11.1, 48.0], [11.1, 48.1]]
+        });
+        assert_eq!(
+            Pins::from_geojson(rooms),
+            Some(Pins {
+                coordinates: vec![(48.0, 11.0), (48.0, 11.1), (48.1, 11.1)]
+            })
+        );
+        let single = serde_json::json!({"type": "MultiPoint", "coordinates": [[11.0, 48.0]]});
+        assert_eq!(Pins::from_geojson(single), None);
+        let point = serde_json::json!({"type": "Point", "coordinates": [11.0, 48.0]});
+        assert_eq!(Pins::from_geojson(point), None);
+        let square = serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [[[11.0, 48.0], [11.1, 48.0], [11.1, 48.1], [11.0, 48.0]]]
+        });
+        assert_eq!(Pins::from_geojson(square.clone()), None);
+        assert!(Footprint::from_geojson(square).is_some());
+    }
+}
@@ -1,17 +1,718 @@
 use std::fmt::{Display, Formatter};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io;
-use std::time::Duration;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime};
 
-use tracing::{error, warn};
+use actix_web::http::header::HttpDate;
+use rand::Rng;
+use tracing::{debug, error, info, warn};
 
+use crate::env_or;
 use crate::limited::vec::LimitedVec;
-use crate::overlays::map::OverlayMapTask;
+use crate::overlays::map::{OverlayMapTask, DEFAULT_TILE_SIZE};
+
+/// Tiles which could not be fetched from any tileserver, exported via `/api/metrics`
+static TILE_FETCH_FAILURES: LazyLock<prometheus::IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "navigatum_api_tile_fetch_failures_total",
+        "Map tiles which could not be downloaded from any tileserver"
+    )
+    .expect("metric can be registered")
+});
+
+/// Lookups of tiles in the [`TileCache`], by if they were a hit or miss, exported via `/api/metrics`
+static TILE_CACHE_LOOKUPS: LazyLock<prometheus::IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "navigatum_api_tile_cache_lookups_total",
+        "Lookups of map tiles in the tile cache, by if they were a hit or miss",
+        &["outcome"]
+    )
+    .expect("metric can be registered")
+});
+
+/// How many lookups in the [`TileCache`] were hits and misses since startup
+pub fn tile_cache_lookups() -> (u64, u64) {
+    let count = |outcome| TILE_CACHE_LOOKUPS.with_label_values(&[outcome]).get();
+    (count("hit"), count("miss"))
+}
+
+/// Where map tiles are fetched from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileServer {
+    /// url templates of the tileservers, in the order in which they are tried. See [`url_template`]
+    urls: Vec<String>,
+    /// what `{s}` in the url templates is replaced with
+    subdomains: Vec<String>,
+    /// how often a failed tile download is retried before giving up
+    retries: u32,
+    /// deadline for downloading a single tile. Exceeding it counts as a transient failure
+    timeout: Duration,
+    /// how many tiles of one preview may be downloaded at the same time
+    max_concurrent_downloads: usize,
+    /// credits for the map data, which the license of the data requires us to show
+    attribution: String,
+    /// width and height of the tiles in pixels, one of the [`TILE_SIZES`]
+    tile_size: u32,
+    cache: Option<TileCache>,
+}
+
+impl Default for TileServer {
+    fn default() -> Self {
+        let dir = std::env::var_os("NAVIGATUM_TILE_CACHE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| std::env::temp_dir().join("tiles"));
+        let max_size = env_or("TILE_CACHE_MAX_SIZE", 2 * 1024 * 1024 * 1024);
+        // misconfigured styles are logged at startup instead of during the first request
+        LazyLock::force(&CONFIGURED_STYLES);
+        let subdomains = std::env::var("TILESERVER_SUBDOMAINS")
+            .unwrap_or_else(|_| DEFAULT_SUBDOMAINS.to_string());
+        let subdomains = parse_subdomains(&subdomains);
+        let tile_size = std::env::var("TILESERVER_TILE_SIZE")
+            .map(|tile_size| parse_tile_size(&tile_size))
+            .unwrap_or(DEFAULT_TILE_SIZE);
+        // tiles of other sizes are stored under the same locations => they must not be mixed up
+        let dir = if tile_size == DEFAULT_TILE_SIZE {
+            dir
+        } else {
+            dir.join(format!("{tile_size}px"))
+        };
+        Self {
+            urls: tileserver_urls(&subdomains, tile_size),
+            subdomains,
+            retries: env_or("TILE_FETCH_RETRIES", 3),
+            timeout: Duration::from_millis(env_or("TILE_FETCH_TIMEOUT_MS", 5_000)),
+            max_concurrent_downloads: env_or::<usize>("TILE_FETCH_CONCURRENCY", 8).max(1),
+            attribution: env_or("TILESERVER_ATTRIBUTION", DEFAULT_ATTRIBUTION.to_string()),
+            tile_size,
+            cache: TileCache::new(dir, max_size),
+        }
+    }
+}
+
+const DEFAULT_TILESERVER_URL: &str = "https://nav.tum.de/tiles/render";
+const DEFAULT_ATTRIBUTION: &str = "© OpenStreetMap contributors";
+/// Where the tiles are below the base url of a tileserver, if it is not configured via a template
+const DEFAULT_TILE_PATH: &str = "/{style}/{z}/{x}/{y}@2x.png";
+/// Like [`DEFAULT_TILE_PATH`], but for the 256px tiles instead of the "retina" ones
+const DEFAULT_SMALL_TILE_PATH: &str = "/{style}/{z}/{x}/{y}.png";
+/// Tile sizes in pixels, which tileservers may be configured with via `TILESERVER_TILE_SIZE`
+const TILE_SIZES: [u32; 2] = [256, DEFAULT_TILE_SIZE];
+const DEFAULT_SUBDOMAINS: &str = "a,b,c";
+/// Placeholders of the url templates, which are replaced by [`tile_url`]
+const PLACEHOLDERS: [&str; 5] = ["{z}", "{x}", "{y}", "{s}", "{style}"];
+
+/// Reads the comma-separated tileservers from `TILESERVER_URLS`.
+///
+/// Invalid templates are skipped at startup, instead of failing every tile download later
+fn tileserver_urls(subdomains: &[String], tile_size: u32) -> Vec<String> {
+    let urls = std::env::var("TILESERVER_URLS").unwrap_or_default();
+    let urls: Vec<String> = parse_tileserver_urls(&urls)
+        .iter()
+        .map(|url| url_template(url, tile_size))
+        .filter(|template| match validate_template(template, subdomains) {
+            Ok(()) => true,
+            Err(e) => {
+                error!(
+                    %template,
+                    error = %e,
+                    "ignoring the tileserver, as its url template is invalid"
+                );
+                false
+            }
+        })
+        .collect();
+    if urls.is_empty() {
+        return vec![url_template(DEFAULT_TILESERVER_URL, tile_size)];
+    }
+    urls
+}
+
+/// Tileservers are configured either by a template like `https://{s}.tile.example.com/{z}/{x}/{y}.png` or by their base url.
+///
+/// Our own tileserver serves the tiles of `tile_size` at [`DEFAULT_TILE_PATH`] or [`DEFAULT_SMALL_TILE_PATH`] below its base url
+fn url_template(url: &str, tile_size: u32) -> String {
+    if url.contains('{') {
+        url.to_string()
+    } else if tile_size == DEFAULT_TILE_SIZE {
+        format!("{url}{DEFAULT_TILE_PATH}")
+    } else {
+        format!("{url}{DEFAULT_SMALL_TILE_PATH}")
+    }
+}
+
+/// Parses `TILESERVER_TILE_SIZE`, falling back to [`DEFAULT_TILE_SIZE`] for sizes other than the [`TILE_SIZES`]
+fn parse_tile_size(tile_size: &str) -> u32 {
+    match tile_size.trim().parse() {
+        Ok(tile_size) if TILE_SIZES.contains(&tile_size) => tile_size,
+        _ => {
+            error!(
+                tile_size,
+                allowed = ?TILE_SIZES,
+                "unsupported tile size, using the default one"
+            );
+            DEFAULT_TILE_SIZE
+        }
+    }
+}
+
+/// Checks that `template` addresses tiles and only contains known [`PLACEHOLDERS`]
+fn validate_template(template: &str, subdomains: &[String]) -> Result<(), String> {
+    for required in ["{z}", "{x}", "{y}"] {
+        if !template.contains(required) {
+            return Err(format!("{required} is missing"));
+        }
+    }
+    if template.contains("{s}") && subdomains.is_empty() {
+        return Err("{s} is used, but TILESERVER_SUBDOMAINS is empty".to_string());
+    }
+    let unknown = PLACEHOLDERS
+        .iter()
+        .fold(template.to_string(), |rest, placeholder| {
+            rest.replace(placeholder, "")
+        });
+    if unknown.contains(['{', '}']) {
+        return Err(format!(
+            "only {} are known placeholders",
+            PLACEHOLDERS.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn parse_subdomains(subdomains: &str) -> Vec<String> {
+    subdomains
+        .split(',')
+        .map(str::trim)
+        .filter(|subdomain| !subdomain.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_tileserver_urls(urls: &str) -> Vec<String> {
+    urls.split(',')
+        .map(|url| url.trim().trim_end_matches('/'))
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl TileServer {
+    /// A tileserver without caching, for testing against [`crate::setup::tests::MockTileServer`]s
+    #[cfg(test)]
+    pub fn mock(urls: &[&str]) -> Self {
+        Self {
+            urls: urls
+                .iter()
+                .map(|url| url_template(url, DEFAULT_TILE_SIZE))
+                .collect(),
+            subdomains: Vec::new(),
+            retries: 3,
+            timeout: Duration::from_secs(5),
+            max_concurrent_downloads: 8,
+            attribution: DEFAULT_ATTRIBUTION.to_string(),
+            tile_size: DEFAULT_TILE_SIZE,
+            cache: None,
+        }
+    }
+
+    /// Caches the tiles in `dir`, like outside of tests
+    #[cfg(test)]
+    pub fn with_cache(self, dir: PathBuf) -> Self {
+        Self {
+            cache: TileCache::new(dir, 1024 * 1024 * 1024),
+            ..self
+        }
+    }
+
+    /// Credits `attribution` below the map instead of the default. Nothing is credited if it is empty
+    #[cfg(test)]
+    pub fn with_attribution(self, attribution: &str) -> Self {
+        Self {
+            attribution: attribution.to_string(),
+            ..self
+        }
+    }
+
+    /// Serves tiles of `tile_size` instead of [`DEFAULT_TILE_SIZE`]
+    #[cfg(test)]
+    pub fn with_tile_size(self, tile_size: u32) -> Self {
+        Self { tile_size, ..self }
+    }
+
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size
+    }
+
+    pub fn max_concurrent_downloads(&self) -> usize {
+        self.max_concurrent_downloads
+    }
+
+    pub fn attribution(&self) -> &str {
+        &self.attribution
+    }
+
+    pub fn cache(&self) -> Option<&TileCache> {
+        self.cache.as_ref()
+    }
+
+    /// Checks that a tileserver delivers a tile within the timeout.
+    ///
+    /// Neither the cache nor retries are used, as they would hide an outage
+    #[tracing::instrument(skip(self))]
+    pub async fn check_reachable(&self) -> anyhow::Result<()> {
+        // the tile of the whole world always exists
+        let location = TileLocation {
+            x: 0,
+            y: 0,
+            z: 0,
+            style: TileStyle::default(),
+        };
+        let mut last_error = anyhow::anyhow!("no tileserver configured");
+        for template in &self.urls {
+            let url = tile_url(template, location, &self.subdomains);
+            match tokio::time::timeout(self.timeout, download_map_image(&url)).await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(e)) => last_error = e.into_error().context(url),
+                Err(_) => {
+                    last_error = anyhow::anyhow!("{url} timed out after {:?}", self.timeout);
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn fetch(&self, location: TileLocation) -> anyhow::Result<LimitedVec<u8>> {
+        // the cache reads and writes files (even on hits, to mark tiles as recently used) => not on the executor
+        if let Some(cache) = self.cache.clone() {
+            let cached = tokio::task::spawn_blocking(move || cache.get(location))
+                .await
+                .expect("reading the tile cache should not panic");
+            let outcome = if cached.is_some() { "hit" } else { "miss" };
+            TILE_CACHE_LOOKUPS.with_label_values(&[outcome]).inc();
+            if let Some(tile) = cached {
+                return Ok(tile);
+            }
+        }
+        let tile = self.download(location).await?;
+        if let Some(cache) = self.cache.clone() {
+            let data = tile.0.clone();
+            tokio::task::spawn_blocking(move || cache.insert(location, &data))
+                .await
+                .expect("writing to the tile cache should not panic");
+        }
+        Ok(tile)
+    }
+
+    /// Downloads a tile from the first tileserver which has it
+    ///
+    /// Tileservers are only skipped once they failed after all retries
+    #[tracing::instrument(skip(self))]
+    async fn download(&self, location: TileLocation) -> anyhow::Result<LimitedVec<u8>> {
+        let mut last_error = anyhow::anyhow!("no tileserver configured");
+        for template in &self.urls {
+            let url = tile_url(template, location, &self.subdomains);
+            match self.download_retrying(&url).await {
+                Ok(tile) => return Ok(tile),
+                Err(e) => {
+                    warn!(url, error = ?e, "tileserver failed, trying the next one");
+                    last_error = e;
+                }
+            }
+        }
+        TILE_FETCH_FAILURES.inc();
+        Err(last_error)
+    }
+
+    /// Downloads a tile, retrying transient failures with exponential backoff
+    async fn download_retrying(&self, url: &str) -> anyhow::Result<LimitedVec<u8>> {
+        let mut attempt = 0;
+        loop {
+            let download = tokio::time::timeout(self.timeout, download_map_image(url))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(DownloadError::Transient(anyhow::anyhow!(
+                        "timed out after {timeout:?}",
+                        timeout = self.timeout
+                    )))
+                });
+            let wait_time = match download {
+                Ok(tile) => return Ok(tile),
+                Err(DownloadError::Permanent(e)) => return Err(e),
+                Err(e) if attempt >= self.retries => {
+                    let e = e.into_error();
+                    return Err(e.context(format!("giving up on {url} after {attempt} retries")));
+                }
+                Err(DownloadError::RateLimited { retry_after }) => {
+                    let wait_time = retry_after
+                        .map(|retry_after| retry_after.min(MAX_RETRY_AFTER))
+                        .unwrap_or_else(|| retry_backoff(attempt));
+                    warn!(url, ?retry_after, retrying_in = ?wait_time, "rate limited by the tileserver");
+                    wait_time
+                }
+                Err(DownloadError::Transient(e)) => {
+                    let wait_time = retry_backoff(attempt);
+                    warn!(url, error = ?e, retrying_in = ?wait_time, "could not download tile");
+                    wait_time
+                }
+            };
+            tokio::time::sleep(wait_time).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Upper bound for waiting on a `Retry-After`, as a preview which takes longer is not useful anyway
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Parses a `Retry-After` header, which is either a number of seconds or a http date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = SystemTime::from(HttpDate::from_str(value).ok()?);
+    // dates in the past mean that we may retry right away
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Exponential backoff starting at 100ms, with up to 50% jitter so that concurrent tiles don't retry in lockstep
+fn retry_backoff(attempt: u32) -> Duration {
+    let base_ms = 100 * 2_u64.pow(attempt.min(10));
+    let jitter_ms = rand::thread_rng().gen_range(0..=base_ms / 2);
+    Duration::from_millis(base_ms + jitter_ms)
+}
+
+/// On-disk cache of map tiles, bounded to `max_size` bytes
+///
+/// Tiles of empty areas or water are often byte-identical.
+/// Their content is thus stored once as blob, named by its hash, while each location only stores which blob it has.
+/// Once the size is exceeded, the least recently used blobs are evicted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// What the [`TileCache`] stores on disk
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TileCacheUsage {
+    /// cached tile locations
+    pub entries: u64,
+    /// distinct tile contents, which the entries share
+    pub blobs: u64,
+    /// size of the entries and blobs
+    pub bytes: u64,
+}
+
+const TILE_CACHE_EVICTION_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// subdirectory of the [`TileCache`] with the content of the tiles
+const TILE_BLOB_DIR: &str = "blobs";
+
+/// Identifies the content of a tile.
+///
+/// Not cryptographically secure, but the tiles come from our own tileserver anyway
+fn content_hash(tile: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tile.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl TileCache {
+    /// Creates the cache in `dir`, including all missing parent directories.
+    ///
+    /// Returns [`None`] if the directory can not be created, as tiles can always be re-downloaded
+    fn new(dir: PathBuf, max_size: u64) -> Option<Self> {
+        match std::fs::create_dir_all(dir.join(TILE_BLOB_DIR)) {
+            Ok(()) => Some(Self { dir, max_size }),
+            Err(e) => {
+                warn!(error = ?e, ?dir, "could not create the tile cache, disabling it");
+                None
+            }
+        }
+    }
+
+    /// the entry of `location`, which contains the hash of its blob
+    fn path(&self, location: TileLocation) -> PathBuf {
+        self.dir.join(format!(
+            "{style}_{z}_{x}_{y}.blob",
+            style = location.style.name(),
+            x = location.x,
+            y = location.y,
+            z = location.z
+        ))
+    }
+
+    fn blob_path(&self, hash: u64) -> PathBuf {
+        self.dir
+            .join(TILE_BLOB_DIR)
+            .join(format!("{hash:016x}.png"))
+    }
+
+    /// the hash of the blob which the entry at `path` refers to
+    fn read_entry(path: &Path) -> Option<u64> {
+        let hash = std::fs::read_to_string(path).ok()?;
+        u64::from_str_radix(hash.trim(), 16).ok()
+    }
+
+    fn get(&self, location: TileLocation) -> Option<LimitedVec<u8>> {
+        let path = self.path(location);
+        let blob_path = self.blob_path(Self::read_entry(&path)?);
+        let Ok(tile) = std::fs::read(&blob_path) else {
+            // the blob was evicted => the entry is stale
+            if let Err(e) = std::fs::remove_file(&path) {
+                debug!(error = ?e, ?path, "could not remove stale tile entry");
+            }
+            return None;
+        };
+        Self::touch(&blob_path);
+        Some(LimitedVec(tile))
+    }
+
+    /// access times are unreliable (noatime/relatime) => the modification time tracks when a blob was last used
+    fn touch(blob_path: &Path) {
+        let touched = std::fs::File::options()
+            .write(true)
+            .open(blob_path)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+        if let Err(e) = touched {
+            debug!(error = ?e, ?blob_path, "could not mark tile as recently used");
+        }
+    }
+
+    fn insert(&self, location: TileLocation, tile: &[u8]) {
+        let hash = content_hash(tile);
+        let blob_path = self.blob_path(hash);
+        let blob = if blob_path.exists() {
+            // an identical tile of another location is stored already
+            Self::touch(&blob_path);
+            Ok(())
+        } else {
+            self.write_atomically(&blob_path, tile)
+        };
+        let path = self.path(location);
+        let res =
+            blob.and_then(|()| self.write_atomically(&path, format!("{hash:016x}").as_bytes()));
+        if let Err(e) = res {
+            warn!(error = ?e, ?path, "could not store tile in the cache");
+        }
+    }
+
+    /// concurrent readers see either the old or the new content of `path`, but never a partially written one
+    fn write_atomically(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        file.write_all(content)?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    pub async fn evict_periodically(self) {
+        let mut interval = tokio::time::interval(TILE_CACHE_EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cache = self.clone();
+            match tokio::task::spawn_blocking(move || cache.evict_least_recently_used()).await {
+                Ok(Ok(evicted)) if evicted > 0 => {
+                    info!(
+                        evicted,
+                        max_size = self.max_size,
+                        "evicted tiles from the cache"
+                    )
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => error!(error = ?e, "could not evict tiles from the cache"),
+                Err(e) => error!(error = ?e, "tile cache eviction panicked"),
+            }
+        }
+    }
+
+    /// Walks the whole cache => this is expensive for large caches
+    pub fn usage(&self) -> io::Result<TileCacheUsage> {
+        let mut usage = TileCacheUsage::default();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.path().extension().is_some_and(|e| e == "blob") {
+                usage.entries += 1;
+                usage.bytes += entry.metadata()?.len();
+            }
+        }
+        for entry in std::fs::read_dir(self.dir.join(TILE_BLOB_DIR))? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                usage.blobs += 1;
+                usage.bytes += metadata.len();
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Removes the least recently used blobs until the cache is smaller than `max_size`.
+    ///
+    /// Returns how many blobs were evicted
+    fn evict_least_recently_used(&self) -> io::Result<usize> {
+        let mut blobs = Vec::new();
+        let mut total_size = 0;
+        for entry in std::fs::read_dir(self.dir.join(TILE_BLOB_DIR))? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            total_size += metadata.len();
+            blobs.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+        if total_size <= self.max_size {
+            return Ok(0);
+        }
+        blobs.sort_unstable_by_key(|(last_used, _, _)| *last_used);
+        let mut evicted = 0;
+        for (_, size, path) in blobs {
+            if total_size <= self.max_size {
+                break;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    total_size -= size;
+                    evicted += 1;
+                }
+                // a concurrent request may have just replaced the blob
+                Err(e) => debug!(error = ?e, ?path, "could not evict tile"),
+            }
+        }
+        self.remove_stale_entries()?;
+        Ok(evicted)
+    }
+
+    /// Removes the entries of evicted blobs, as well as tiles stored before blobs were shared
+    fn remove_stale_entries(&self) -> io::Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let is_stale = match path.extension().and_then(|e| e.to_str()) {
+                Some("blob") => {
+                    Self::read_entry(&path).is_none_or(|hash| !self.blob_path(hash).exists())
+                }
+                Some("png") => true,
+                _ => false,
+            };
+            if is_stale {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    debug!(error = ?e, ?path, "could not remove stale tile entry");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The map style of the tiles, as served by the tileserver
+#[derive(Hash, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum TileStyle {
+    #[default]
+    Light,
+    Dark,
+    /// One of the [`CONFIGURED_STYLES`], e.g. `terrain`
+    Configured(&'static str),
+}
+
+/// Further styles of the tileserver, from the comma-separated `TILESERVER_STYLES`
+///
+/// The names end up in urls and in the paths of the [`TileCache`] => only simple names are allowed
+static CONFIGURED_STYLES: LazyLock<Vec<String>> = LazyLock::new(|| {
+    let styles = std::env::var("TILESERVER_STYLES").unwrap_or_default();
+    let styles = parse_styles(&styles);
+    if !styles.is_empty() {
+        info!(?styles, "tileserver styles can be chosen");
+    }
+    styles
+});
+
+fn parse_styles(styles: &str) -> Vec<String> {
+    styles
+        .split(',')
+        .map(str::trim)
+        .filter(|style| !style.is_empty())
+        .filter(|style| {
+            let is_simple = style
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !is_simple {
+                warn!(style, "ignoring the tileserver style, as its name is not just letters, digits, '-' and '_'");
+            }
+            is_simple
+        })
+        .map(String::from)
+        .collect()
+}
+
+impl TileStyle {
+    fn name(self) -> &'static str {
+        match self {
+            TileStyle::Light => "navigatum-basemap",
+            TileStyle::Dark => "navigatum-dark",
+            TileStyle::Configured(name) => name,
+        }
+    }
+
+    /// The style `name`, if it is one of `allowed`
+    fn allowed(name: &str, allowed: &'static [String]) -> Result<Self, String> {
+        if let Some(style) = allowed.iter().find(|style| *style == name) {
+            return Ok(TileStyle::Configured(style));
+        }
+        if allowed.is_empty() {
+            return Err(format!(
+                "style={name} is not allowed, as no styles are configured"
+            ));
+        }
+        Err(format!(
+            "style={name} is not allowed. It has to be one of {}",
+            allowed.join(", ")
+        ))
+    }
+}
+
+/// Only the [`CONFIGURED_STYLES`] can be parsed, as the built-in ones are chosen via the theme
+impl FromStr for TileStyle {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::allowed(name, &CONFIGURED_STYLES)
+    }
+}
 
 #[derive(Hash, Debug, Copy, Clone)]
 struct TileLocation {
     x: u32,
     y: u32,
     z: u32,
+    style: TileStyle,
+}
+
+/// Fills the [`PLACEHOLDERS`] of `template` for `location`
+fn tile_url(template: &str, location: TileLocation, subdomains: &[String]) -> String {
+    let url = template
+        .replace("{style}", location.style.name())
+        .replace("{z}", &location.z.to_string())
+        .replace("{x}", &location.x.to_string())
+        .replace("{y}", &location.y.to_string());
+    match subdomain(location, subdomains) {
+        Some(subdomain) => url.replace("{s}", subdomain),
+        None => url,
+    }
+}
+
+/// Like in leaflet, neighbouring tiles are spread over the subdomains, while each tile always uses the same one.
+///
+/// Browsers limit the connections per host => more tiles are downloaded at the same time
+fn subdomain(location: TileLocation, subdomains: &[String]) -> Option<&str> {
+    if subdomains.is_empty() {
+        return None;
+    }
+    let index = (location.x as usize + location.y as usize) % subdomains.len();
+    Some(&subdomains[index])
 }
 
 impl Display for TileLocation {
@@ -20,6 +721,7 @@ impl Display for TileLocation {
             .field(&self.x)
             .field(&self.y)
             .field(&self.z)
+            .field(&self.style)
             .finish()
     }
 }
@@ -32,11 +734,13 @@ pub struct MapImageDownloadTask {
 
 impl From<&OverlayMapTask> for MapImageDownloadTask {
     fn from(overlay: &OverlayMapTask) -> Self {
+        let (x, y) = overlay.center();
         Self {
             location: TileLocation {
-                x: overlay.x as u32,
-                y: overlay.y as u32,
+                x: x as u32,
+                y: y as u32,
                 z: overlay.z,
+                style: overlay.style,
             },
             index: (0, 0),
         }
@@ -60,7 +764,7 @@ impl MapImageDownloadTask {
             location: TileLocation {
                 x: Self::offset_zoom_aware(self.location.z, self.location.x, x_offset),
                 y: Self::offset_zoom_aware(self.location.z, self.location.y, y_offset),
-                z: self.location.z,
+                ..self.location
             },
             ..self
         }
@@ -74,9 +778,9 @@ impl MapImageDownloadTask {
     }
 
     // type and create are specified, because a custom conversion is needed
-    #[tracing::instrument(ret(level = tracing::Level::TRACE))]
-    pub async fn fulfill(self) -> Option<((u32, u32), image::DynamicImage)> {
-        let raw_tile = download_map_image(self.location).await;
+    #[tracing::instrument(skip(tiles), ret(level = tracing::Level::TRACE))]
+    pub async fn fulfill(self, tiles: &TileServer) -> Option<((u32, u32), image::DynamicImage)> {
+        let raw_tile = tiles.fetch(self.location).await;
         match raw_tile {
             Ok(bytes) => match image::load_from_memory(&bytes.0) {
                 Ok(img) => Some((self.index, img)),
@@ -93,45 +797,87 @@ impl MapImageDownloadTask {
     }
 }
 
+#[derive(Debug)]
+enum DownloadError {
+    /// the tileserver is overloaded, restarting or unreachable => retrying may help
+    Transient(anyhow::Error),
+    /// the tileserver asked us to slow down, optionally telling us for how long
+    RateLimited { retry_after: Option<Duration> },
+    /// the tile does not exist => retrying is pointless
+    Permanent(anyhow::Error),
+}
+
+impl DownloadError {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            Self::Transient(e) | Self::Permanent(e) => e,
+            Self::RateLimited { .. } => anyhow::anyhow!("rate limited by the tileserver"),
+        }
+    }
+}
+
+/// Shared by all tile downloads, as building a client is slow and it keeps the connections to the tileservers alive
+static TILE_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
 #[tracing::instrument]
-async fn download_map_image(location: TileLocation) -> anyhow::Result<LimitedVec<u8>> {
-    let url = format!(
-        "https://nav.tum.de/tiles/render/navigatum-basemap/{z}/{x}/{y}@2x.png",
-        x = location.x,
-        y = location.y,
-        z = location.z
-    );
-    for i in 1..5 {
-        let response = reqwest::get(&url).await?;
-        let status = response.status();
-        if status.as_u16() == 400 {
-            error!(url, ?status, "could not find {location:?}");
-            return Err(io::Error::other("could not find requested tile").into());
-        }
-        let bytes = response.bytes().await?;
-        // wait with exponential backoff
-        let size = bytes.len();
-        if size > 500 {
-            return Ok(LimitedVec(bytes.into()));
-        }
-        let wait_time_ms = 1.5_f32.powi(i).round() as u64;
-        let wait_time = Duration::from_millis(wait_time_ms);
-        warn!(
-            url,
-            ?status,
-            retrying_in= ?wait_time,
-            "retrying because response is only {size}B"
-        );
-        tokio::time::sleep(wait_time).await;
+async fn download_map_image(url: &str) -> Result<LimitedVec<u8>, DownloadError> {
+    // network errors and timeouts are transient
+    let response = TILE_CLIENT
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| DownloadError::Transient(e.into()))?;
+    let status = response.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+        return Err(DownloadError::RateLimited { retry_after });
     }
-    Err(anyhow::anyhow!("Got only short Responses from {url}"))
+    if status.is_server_error() {
+        return Err(DownloadError::Transient(anyhow::anyhow!(
+            "tileserver responded with {status}"
+        )));
+    }
+    if !status.is_success() {
+        error!(url, ?status, "could not find tile");
+        return Err(DownloadError::Permanent(
+            io::Error::other(format!("could not find requested tile: {status}")).into(),
+        ));
+    }
+    // during maintenance, the tileserver answers with an html page => caching it would poison the tile cache
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if let Some(content_type) = content_type.filter(|c| !c.starts_with("image/")) {
+        return Err(DownloadError::Transient(anyhow::anyhow!(
+            "response is {content_type} instead of an image"
+        )));
+    }
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| DownloadError::Transient(e.into()))?;
+    // the tileserver sometimes answers with tiny placeholder responses while it is still rendering
+    let size = bytes.len();
+    if size <= 500 {
+        return Err(DownloadError::Transient(anyhow::anyhow!(
+            "response is only {size}B"
+        )));
+    }
+    Ok(LimitedVec(bytes.into()))
 }
 
 #[cfg(test)]
 mod tests {
+    use actix_web::HttpResponse;
     use pretty_assertions::assert_eq;
 
     use super::*;
+    use crate::setup::tests::MockTileServer;
 
     #[test]
     /// Zoom 0 has 1 tile
@@ -152,4 +898,534 @@ mod tests {
         assert_eq!(MapImageDownloadTask::offset_zoom_aware(1, 0, 4), 0);
         assert_eq!(MapImageDownloadTask::offset_zoom_aware(1, 0, 5), 1);
     }
+
+    const MOCK_TILE: [u8; 1024] = [42; 1024];
+
+    #[actix_web::test]
+    async fn test_retries_transient_failures() {
+        let mock = MockTileServer::new(|n| async move {
+            if n < 2 {
+                HttpResponse::ServiceUnavailable().finish()
+            } else {
+                HttpResponse::Ok().body(MOCK_TILE.to_vec())
+            }
+        })
+        .await;
+        let tile = TileServer::mock(&[&mock.url])
+            .fetch(TileLocation {
+                x: 1,
+                y: 2,
+                z: 3,
+                style: TileStyle::Light,
+            })
+            .await
+            .unwrap();
+        assert_eq!(tile, LimitedVec(MOCK_TILE.to_vec()));
+        assert_eq!(mock.requests(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_does_not_retry_missing_tiles() {
+        let mock = MockTileServer::new(|_| async { HttpResponse::NotFound().finish() }).await;
+        let tile = TileServer::mock(&[&mock.url])
+            .fetch(TileLocation {
+                x: 1,
+                y: 2,
+                z: 3,
+                style: TileStyle::Light,
+            })
+            .await;
+        assert!(tile.is_err());
+        assert_eq!(mock.requests(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_gives_up_after_retries() {
+        let mock = MockTileServer::new(|_| async { HttpResponse::BadGateway().finish() }).await;
+        let tile = TileServer::mock(&[&mock.url])
+            .fetch(TileLocation {
+                x: 1,
+                y: 2,
+                z: 3,
+                style: TileStyle::Light,
+            })
+            .await;
+        assert!(tile.is_err());
+        assert_eq!(mock.requests(), 4);
+    }
+
+    #[actix_web::test]
+    async fn test_falls_back_to_secondary_tileserver() {
+        let secondary =
+            MockTileServer::new(|_| async { HttpResponse::Ok().body(MOCK_TILE.to_vec()) }).await;
+        // nothing listens on port 1
+        let tiles = TileServer::mock(&["http://127.0.0.1:1", &secondary.url]);
+        let tile = tiles
+            .fetch(TileLocation {
+                x: 1,
+                y: 2,
+                z: 3,
+                style: TileStyle::Light,
+            })
+            .await
+            .unwrap();
+        assert_eq!(tile, LimitedVec(MOCK_TILE.to_vec()));
+        assert_eq!(secondary.requests(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_slow_tiles_time_out() {
+        let mock = MockTileServer::new(|_| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            HttpResponse::Ok().body(MOCK_TILE.to_vec())
+        })
+        .await;
+        let tiles = TileServer {
+            retries: 1,
+            timeout: Duration::from_millis(100),
+            ..TileServer::mock(&[&mock.url])
+        };
+        let start = std::time::Instant::now();
+        let tile = tiles
+            .fetch(TileLocation {
+                x: 1,
+                y: 2,
+                z: 3,
+                style: TileStyle::Light,
+            })
+            .await;
+        assert!(tile.is_err());
+        // a timeout is retried like any other transient failure
+        assert_eq!(mock.requests(), 2);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[actix_web::test]
+    async fn test_respects_retry_after() {
+        let mock = MockTileServer::new(|n| async move {
+            if n == 0 {
+                HttpResponse::TooManyRequests()
+                    .insert_header(("Retry-After", "1"))
+                    .finish()
+            } else {
+                HttpResponse::Ok().body(MOCK_TILE.to_vec())
+            }
+        })
+        .await;
+        let start = std::time::Instant::now();
+        let tile = TileServer::mock(&[&mock.url])
+            .fetch(TileLocation {
+                x: 1,
+                y: 2,
+                z: 3,
+                style: TileStyle::Light,
+            })
+            .await
+            .unwrap();
+        assert_eq!(tile, LimitedVec(MOCK_TILE.to_vec()));
+        assert_eq!(mock.requests(), 2);
+        // the backoff without Retry-After would be ~100ms
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[actix_web::test]
+    async fn test_gives_up_if_always_rate_limited() {
+        let mock = MockTileServer::new(|_| async {
+            HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", "0"))
+                .finish()
+        })
+        .await;
+        let tile = TileServer::mock(&[&mock.url])
+            .fetch(TileLocation {
+                x: 1,
+                y: 2,
+                z: 3,
+                style: TileStyle::Light,
+            })
+            .await;
+        assert!(tile.is_err());
+        assert_eq!(mock.requests(), 4);
+    }
+
+    #[actix_web::test]
+    async fn non_image_responses_are_not_cached() {
+        let maintenance = "<html><body>Down for maintenance</body></html>".repeat(20);
+        let mock = MockTileServer::new(move |n| {
+            let maintenance = maintenance.clone();
+            async move {
+                if n == 0 {
+                    HttpResponse::Ok()
+                        .content_type("text/html")
+                        .body(maintenance)
+                } else {
+                    HttpResponse::Ok()
+                        .content_type("image/png")
+                        .body(MOCK_TILE.to_vec())
+                }
+            }
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = TileServer {
+            retries: 0,
+            cache: TileCache::new(dir.path().to_path_buf(), 1024 * 1024),
+            ..TileServer::mock(&[&mock.url])
+        };
+        let location = TileLocation {
+            x: 1,
+            y: 2,
+            z: 3,
+            style: TileStyle::Light,
+        };
+        let cache = tiles.cache().unwrap();
+        assert!(tiles.fetch(location).await.is_err());
+        assert_eq!(cache.get(location), None);
+        // once the tileserver is back, the tile is fetched and cached as usual
+        let tile = tiles.fetch(location).await.unwrap();
+        assert_eq!(tile, LimitedVec(MOCK_TILE.to_vec()));
+        assert_eq!(cache.get(location), Some(tile));
+        assert_eq!(mock.requests(), 2);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        // 1445412480 is Wed, 21 Oct 2015 07:28:00 GMT
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[test]
+    fn test_tile_url_depends_on_style() {
+        let location = |style| TileLocation {
+            x: 1,
+            y: 2,
+            z: 3,
+            style,
+        };
+        let template = url_template("https://nav.tum.de/tiles/render", DEFAULT_TILE_SIZE);
+        assert_eq!(
+            tile_url(&template, location(TileStyle::Light), &[]),
+            "https://nav.tum.de/tiles/render/navigatum-basemap/3/1/2@2x.png"
+        );
+        assert_eq!(
+            tile_url(&template, location(TileStyle::Dark), &[]),
+            "https://nav.tum.de/tiles/render/navigatum-dark/3/1/2@2x.png"
+        );
+    }
+
+    #[test]
+    fn small_tiles_are_not_the_retina_ones() {
+        let location = TileLocation {
+            x: 1,
+            y: 2,
+            z: 3,
+            style: TileStyle::Light,
+        };
+        let template = url_template("https://nav.tum.de/tiles/render", 256);
+        assert_eq!(
+            tile_url(&template, location, &[]),
+            "https://nav.tum.de/tiles/render/navigatum-basemap/3/1/2.png"
+        );
+        // templates already say which tiles they address
+        let template = "https://tile.example.com/{z}/{x}/{y}.png";
+        assert_eq!(url_template(template, 256), template);
+        assert_eq!(parse_tile_size(" 256 "), 256);
+        assert_eq!(parse_tile_size("512"), 512);
+        for unsupported in ["1024", "300", "large", ""] {
+            assert_eq!(
+                parse_tile_size(unsupported),
+                DEFAULT_TILE_SIZE,
+                "{unsupported}"
+            );
+        }
+    }
+
+    #[test]
+    fn templates_round_robin_subdomains() {
+        let template = url_template(
+            "https://{s}.tile.example.com/{z}/{x}/{y}.png",
+            DEFAULT_TILE_SIZE,
+        );
+        let subdomains = parse_subdomains(DEFAULT_SUBDOMAINS);
+        assert_eq!(validate_template(&template, &subdomains), Ok(()));
+        let url = |x, y| {
+            let location = TileLocation {
+                x,
+                y,
+                z: 17,
+                style: TileStyle::Light,
+            };
+            tile_url(&template, location, &subdomains)
+        };
+        let row: Vec<String> = (0..4).map(|x| url(x, 5)).collect();
+        assert_eq!(
+            row,
+            [
+                "https://c.tile.example.com/17/0/5.png",
+                "https://a.tile.example.com/17/1/5.png",
+                "https://b.tile.example.com/17/2/5.png",
+                "https://c.tile.example.com/17/3/5.png",
+            ]
+        );
+        // the same tile always comes from the same subdomain
+        assert_eq!(url(2, 5), url(2, 5));
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        let subdomains = parse_subdomains("a,b");
+        for template in [
+            "https://tiles.example.com/{x}/{y}.png",
+            "https://tiles.example.com/{z}/{x}/{y}/{scale}.png",
+            "https://tiles.example.com/{z}/{x}/{y.png",
+        ] {
+            assert!(
+                validate_template(template, &subdomains).is_err(),
+                "{template} should be rejected"
+            );
+        }
+        let template = "https://{s}.tiles.example.com/{style}/{z}/{x}/{y}.png";
+        assert_eq!(validate_template(template, &subdomains), Ok(()));
+        assert!(validate_template(template, &[]).is_err());
+        assert_eq!(
+            validate_template(
+                &url_template(DEFAULT_TILESERVER_URL, DEFAULT_TILE_SIZE),
+                &[]
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn configured_styles_are_allow_listed() {
+        static ALLOWED: LazyLock<Vec<String>> =
+            LazyLock::new(|| parse_styles(" streets, terrain,,minimal,../etc "));
+        assert_eq!(*ALLOWED, ["streets", "terrain", "minimal"]);
+        let terrain = TileStyle::allowed("terrain", &ALLOWED).unwrap();
+        assert_eq!(terrain, TileStyle::Configured("terrain"));
+        let location = TileLocation {
+            x: 1,
+            y: 2,
+            z: 3,
+            style: terrain,
+        };
+        assert_eq!(
+            tile_url(
+                &url_template("https://nav.tum.de/tiles/render", DEFAULT_TILE_SIZE),
+                location,
+                &[]
+            ),
+            "https://nav.tum.de/tiles/render/terrain/3/1/2@2x.png"
+        );
+
+        let err = TileStyle::allowed("satellite", &ALLOWED).unwrap_err();
+        assert_eq!(
+            err,
+            "style=satellite is not allowed. It has to be one of streets, terrain, minimal"
+        );
+        static NONE: Vec<String> = Vec::new();
+        assert!(TileStyle::allowed("terrain", &NONE).is_err());
+    }
+
+    #[test]
+    fn test_parse_tileserver_urls() {
+        assert_eq!(parse_tileserver_urls(""), Vec::<String>::new());
+        assert_eq!(
+            parse_tileserver_urls("https://a.example/tiles/, https://b.example,,"),
+            vec![
+                "https://a.example/tiles".to_string(),
+                "https://b.example".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_backoff_grows() {
+        for attempt in 0..5 {
+            let base = Duration::from_millis(100 * 2_u64.pow(attempt));
+            let wait_time = retry_backoff(attempt);
+            assert!(wait_time >= base, "{wait_time:?} < {base:?}");
+            assert!(wait_time <= base * 3 / 2, "{wait_time:?} > 1.5 * {base:?}");
+        }
+    }
+
+    #[test]
+    fn tile_cache_creates_nested_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a").join("b").join("tiles");
+        let cache = TileCache::new(nested.clone(), 1024);
+        assert!(cache.is_some());
+        assert!(nested.is_dir());
+    }
+
+    #[test]
+    fn tile_cache_is_disabled_if_dir_cannot_be_created() {
+        let dir = tempfile::tempdir().unwrap();
+        // a file is used as the parent, so this fails even when running as root
+        let file = dir.path().join("not_a_dir");
+        std::fs::write(&file, b"").unwrap();
+        assert_eq!(TileCache::new(file.join("tiles"), 1024), None);
+    }
+
+    #[test]
+    fn tile_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::new(dir.path().to_path_buf(), 1024).unwrap();
+        let location = TileLocation {
+            x: 1,
+            y: 2,
+            z: 3,
+            style: TileStyle::Light,
+        };
+        assert_eq!(cache.get(location), None);
+        cache.insert(location, b"tile");
+        assert_eq!(cache.get(location), Some(LimitedVec(b"tile".to_vec())));
+        assert_eq!(
+            cache.get(TileLocation {
+                x: 2,
+                y: 1,
+                z: 3,
+                style: TileStyle::Light
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn tile_cache_evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::new(dir.path().to_path_buf(), 550).unwrap();
+        let start = SystemTime::now() - Duration::from_secs(60 * 60);
+        for x in 0..10 {
+            let location = TileLocation {
+                x,
+                y: 0,
+                z: 0,
+                style: TileStyle::Light,
+            };
+            let tile = [x as u8; 100];
+            cache.insert(location, &tile);
+            std::fs::File::options()
+                .write(true)
+                .open(cache.blob_path(content_hash(&tile)))
+                .unwrap()
+                .set_modified(start + Duration::from_secs(u64::from(x)))
+                .unwrap();
+        }
+        // using a tile makes it recently used
+        assert!(cache
+            .get(TileLocation {
+                x: 0,
+                y: 0,
+                z: 0,
+                style: TileStyle::Light
+            })
+            .is_some());
+
+        assert_eq!(cache.evict_least_recently_used().unwrap(), 5);
+        let remaining_size: u64 = std::fs::read_dir(dir.path().join(TILE_BLOB_DIR))
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(remaining_size <= 550);
+        let still_cached = (0..10)
+            .filter(|x| {
+                cache
+                    .path(TileLocation {
+                        x: *x,
+                        y: 0,
+                        z: 0,
+                        style: TileStyle::Light,
+                    })
+                    .exists()
+            })
+            .collect::<Vec<u32>>();
+        // the entries of evicted blobs are removed too
+        assert_eq!(still_cached, vec![0, 6, 7, 8, 9]);
+        // below the limit, nothing is evicted
+        assert_eq!(cache.evict_least_recently_used().unwrap(), 0);
+    }
+
+    #[test]
+    fn identical_tiles_share_one_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::new(dir.path().to_path_buf(), 1024).unwrap();
+        let location = |x| TileLocation {
+            x,
+            y: 0,
+            z: 0,
+            style: TileStyle::Light,
+        };
+        let blobs = || {
+            std::fs::read_dir(dir.path().join(TILE_BLOB_DIR))
+                .unwrap()
+                .count()
+        };
+        // e.g. two tiles of water
+        cache.insert(location(0), b"water");
+        cache.insert(location(1), b"water");
+        assert_eq!(blobs(), 1);
+        assert_eq!(cache.get(location(0)), Some(LimitedVec(b"water".to_vec())));
+        assert_eq!(cache.get(location(1)), Some(LimitedVec(b"water".to_vec())));
+
+        cache.insert(location(2), b"campus");
+        assert_eq!(blobs(), 2);
+        assert_eq!(cache.get(location(2)), Some(LimitedVec(b"campus".to_vec())));
+        // a changed tile refers to its new blob
+        cache.insert(location(1), b"campus");
+        assert_eq!(cache.get(location(1)), Some(LimitedVec(b"campus".to_vec())));
+        assert_eq!(cache.get(location(0)), Some(LimitedVec(b"water".to_vec())));
+    }
+
+    #[test]
+    fn tile_cache_usage_counts_shared_blobs_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::new(dir.path().to_path_buf(), 1024).unwrap();
+        assert_eq!(cache.usage().unwrap(), TileCacheUsage::default());
+        for x in 0..3 {
+            let location = TileLocation {
+                x,
+                y: 0,
+                z: 1,
+                style: TileStyle::Light,
+            };
+            cache.insert(location, &[0; 100]);
+        }
+        let usage = cache.usage().unwrap();
+        assert_eq!(usage.entries, 3);
+        assert_eq!(usage.blobs, 1);
+        // the entries only contain the hash of the blob
+        assert_eq!(usage.bytes, 100 + 3 * 16);
+    }
+
+    #[test]
+    fn entries_of_evicted_blobs_are_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::new(dir.path().to_path_buf(), 1024).unwrap();
+        let location = TileLocation {
+            x: 1,
+            y: 2,
+            z: 3,
+            style: TileStyle::Light,
+        };
+        cache.insert(location, b"tile");
+        std::fs::remove_file(cache.blob_path(content_hash(b"tile"))).unwrap();
+        assert_eq!(cache.get(location), None);
+        assert!(!cache.path(location).exists());
+    }
 }
@@ -24,19 +24,102 @@ enum LanguageOptions {
 #[serde(default)]
 pub struct LangQueryArgs {
     /// The language you want your preview to be in. If either this or the query parameter is set to en, this will be delivered.
-    lang: LanguageOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lang: Option<LanguageOptions>,
 }
 
 impl LangQueryArgs {
     pub fn should_use_english(self) -> bool {
-        self.lang == LanguageOptions::En
+        self.lang == Some(LanguageOptions::En)
+    }
+    /// Like [`Self::should_use_english`], but respects the `Accept-Language` header if no `lang` was requested.
+    ///
+    /// An explicit `lang` always wins over the header
+    pub fn should_use_english_or_accept(self, accept_language: Option<&str>) -> bool {
+        match (self.lang, accept_language.and_then(negotiate_language)) {
+            (Some(lang), _) | (None, Some(lang)) => lang == LanguageOptions::En,
+            (None, None) => false,
+        }
     }
 }
+
+/// Picks the language we support with the highest quality from an `Accept-Language` header.
+///
+/// Only the primary subtag matters (`en-GB` is `en`). On equal qualities, earlier listed languages win.
+fn negotiate_language(accept_language: &str) -> Option<LanguageOptions> {
+    let mut best: Option<(f32, LanguageOptions)> = None;
+    for range in accept_language.split(',') {
+        let mut parts = range.split(';').map(str::trim);
+        let tag = parts.next().unwrap_or_default();
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+        let Some(quality) = quality.filter(|q| *q > 0.0) else {
+            continue;
+        };
+        let primary = tag.split('-').next().unwrap_or_default();
+        let lang = if primary.eq_ignore_ascii_case("de") {
+            LanguageOptions::De
+        } else if primary.eq_ignore_ascii_case("en") {
+            LanguageOptions::En
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(best_quality, _)| quality > best_quality) {
+            best = Some((quality, lang));
+        }
+    }
+    best.map(|(_, lang)| lang)
+}
 impl Display for LangQueryArgs {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self.lang {
+        match self.lang.unwrap_or_default() {
             LanguageOptions::En => f.write_str("en"),
             LanguageOptions::De => f.write_str("de"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn accept_language_negotiation() {
+        let cases = [
+            ("de-DE,de;q=0.9,en;q=0.8", Some(LanguageOptions::De)),
+            ("en-US,en;q=0.9,de;q=0.8", Some(LanguageOptions::En)),
+            (
+                "fr-FR,fr;q=0.9,en;q=0.8,de;q=0.7",
+                Some(LanguageOptions::En),
+            ),
+            ("de;q=0.5,en;q=0.6", Some(LanguageOptions::En)),
+            ("EN-gb", Some(LanguageOptions::En)),
+            ("en;q=0,de;q=0.1", Some(LanguageOptions::De)),
+            ("en,de", Some(LanguageOptions::En)),
+            ("fr,*;q=0.5", None),
+            ("", None),
+        ];
+        for (accept_language, expected) in cases {
+            assert_eq!(
+                negotiate_language(accept_language),
+                expected,
+                "Accept-Language: {accept_language}"
+            );
+        }
+    }
+
+    #[test]
+    fn explicit_lang_wins() {
+        let args = |lang| LangQueryArgs { lang };
+        let german = Some("de-DE,de;q=0.9,en;q=0.8");
+        let english = Some("en-US,en;q=0.9");
+        assert!(!args(None).should_use_english_or_accept(german));
+        assert!(args(None).should_use_english_or_accept(english));
+        assert!(!args(None).should_use_english_or_accept(None));
+        assert!(args(Some(LanguageOptions::En)).should_use_english_or_accept(german));
+        assert!(!args(Some(LanguageOptions::De)).should_use_english_or_accept(english));
+    }
+}
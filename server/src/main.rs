@@ -1,6 +1,7 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use actix_cors::Cors;
 use actix_governor::{GlobalKeyExtractor, GovernorConfigBuilder};
@@ -11,7 +12,7 @@ use meilisearch_sdk::client::Client;
 use sentry::SessionMode;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::prelude::*;
-use sqlx::{PgPool, Pool, Postgres};
+use sqlx::PgPool;
 use tokio::sync::{Barrier, RwLock};
 use tracing::{debug_span, error, info};
 use tracing_actix_web::TracingLogger;
@@ -40,12 +41,23 @@ pub struct AppData {
     /// necessary, as otherwise we could return empty results during initialisation
     meilisearch_initialised: Arc<RwLock<()>>,
     valhalla: external::valhalla::ValhallaWrapper,
+    preview: locations::preview::PreviewConfig,
 }
 
 impl AppData {
     async fn new() -> Self {
         let pool = PgPoolOptions::new()
             .min_connections(2)
+            .max_connections(env_or("POSTGRES_POOL_MAX_CONNECTIONS", 10))
+            // during an outage, requests should fail fast instead of queuing up
+            .acquire_timeout(Duration::from_millis(env_or(
+                "POSTGRES_POOL_ACQUIRE_TIMEOUT_MS",
+                5_000,
+            )))
+            .idle_timeout(Duration::from_secs(env_or(
+                "POSTGRES_POOL_IDLE_TIMEOUT_S",
+                600,
+            )))
             .connect(&connection_string())
             .await
             .expect("make sure that postgis is running in the background");
@@ -58,6 +70,7 @@ impl From<PgPool> for AppData {
             pool,
             meilisearch_initialised: Arc::new(Default::default()),
             valhalla: external::valhalla::ValhallaWrapper::default(),
+            preview: locations::preview::PreviewConfig::default(),
         }
     }
 }
@@ -112,6 +125,17 @@ fn connection_string() -> String {
     format!("postgres://{username}:{password}@{url}/{db}")
 }
 
+/// parses the environment variable `key`, falling back to `default` if it is not set or invalid
+pub(crate) fn env_or<T: std::str::FromStr + std::fmt::Display>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            tracing::warn!(key, value, %default, "could not parse environment variable, using the default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
 pub fn setup_logging() {
     use tracing_subscriber::filter::EnvFilter;
     use tracing_subscriber::fmt::Layer;
@@ -164,12 +188,10 @@ fn main() -> anyhow::Result<()> {
     actix_web::rt::System::new().block_on(async { run().await })?;
     Ok(())
 }
-#[tracing::instrument(skip(pool, meilisearch_initialised, initialisation_started))]
-async fn run_maintenance_work(
-    pool: Pool<Postgres>,
-    meilisearch_initialised: Arc<RwLock<()>>,
-    initialisation_started: Arc<Barrier>,
-) {
+#[tracing::instrument(skip(data, initialisation_started))]
+async fn run_maintenance_work(data: AppData, initialisation_started: Arc<Barrier>) {
+    let pool = data.pool.clone();
+    let meilisearch_initialised = data.meilisearch_initialised.clone();
     if std::env::var("SKIP_MS_SETUP") != Ok("true".to_string()) {
         let _ = debug_span!("updating meilisearch data").enter();
         let _ = meilisearch_initialised.write().await;
@@ -196,9 +218,27 @@ async fn run_maintenance_work(
     set.spawn(async move { refresh::indoor_maps::all_entries(&map_pool).await });
     let cal_pool = pool.clone();
     set.spawn(async move { refresh::calendar::all_entries(&cal_pool).await });
+    // the previews can only be rendered once the data is loaded
+    let prime_ids = preview_prime_ids();
+    if !prime_ids.is_empty() {
+        set.spawn(async move {
+            locations::preview::prime_cache(&data, prime_ids).await;
+        });
+    }
     set.join_all().await;
 }
 
+/// Locations whose previews are rendered at startup, from the comma-separated `PREVIEW_PRIME_IDS`
+fn preview_prime_ids() -> Vec<String> {
+    std::env::var("PREVIEW_PRIME_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 /// we split main and run because otherwise sentry could not be properly instrumented
 async fn run() -> anyhow::Result<()> {
     let data = AppData::new().await;
@@ -206,11 +246,22 @@ async fn run() -> anyhow::Result<()> {
     // without this barrier an external client might race the RWLock for meilisearch_initialised and gain the read lock before it is allowed
     let initialisation_started = Arc::new(Barrier::new(2));
     let maintenance_thread = tokio::spawn(run_maintenance_work(
-        data.pool.clone(),
-        data.meilisearch_initialised.clone(),
+        data.clone(),
         initialisation_started.clone(),
     ));
+    let tile_cache_eviction = data
+        .preview
+        .tile_server()
+        .cache()
+        .cloned()
+        .map(|cache| tokio::spawn(async move { cache.evict_periodically().await }));
+    let preview_cache_eviction = data
+        .preview
+        .cache()
+        .cloned()
+        .map(|cache| tokio::spawn(async move { cache.evict_periodically().await }));
 
+    locations::preview::preload_assets();
     let prometheus = build_metrics();
     let shutdown_pool_clone = data.pool.clone();
     initialisation_started.wait().await;
@@ -235,6 +286,7 @@ async fn run() -> anyhow::Result<()> {
         docs::add_openapi_docs(
             App::new()
                 .wrap(Etag)
+                .wrap(middleware::from_fn(locations::preview::keep_preview_etag))
                 .wrap(prometheus.clone())
                 .wrap(cors)
                 .wrap(TracingLogger::default())
@@ -253,6 +305,12 @@ async fn run() -> anyhow::Result<()> {
                 .service(locations::details::get_handler)
                 .service(locations::nearby::nearby_handler)
                 .service(locations::preview::maps_handler)
+                .service(locations::preview::maps_head_handler)
+                .service(locations::preview::meta_handler)
+                .service(locations::preview::ready_handler)
+                .service(locations::preview::batch_handler)
+                .service(locations::preview::random_handler)
+                .service(locations::preview::cache_stats_handler)
                 .service(feedback::post_feedback::send_feedback)
                 .service(feedback::proposed_edits::propose_edits)
                 .service(
@@ -267,6 +325,12 @@ async fn run() -> anyhow::Result<()> {
     .run()
     .await?;
     maintenance_thread.abort();
+    if let Some(tile_cache_eviction) = tile_cache_eviction {
+        tile_cache_eviction.abort();
+    }
+    if let Some(preview_cache_eviction) = preview_cache_eviction {
+        preview_cache_eviction.abort();
+    }
     shutdown_pool_clone.close().await;
     Ok(())
 }
@@ -282,6 +346,8 @@ fn build_metrics() -> PrometheusMetrics {
     PrometheusMetricsBuilder::new("navigatum_api")
         .endpoint("/api/metrics")
         .const_labels(labels)
+        // shared with the metrics of the individual endpoints
+        .registry(prometheus::default_registry().clone())
         .build()
         .expect("specified metrics are valid")
 }
@@ -0,0 +1,232 @@
+//! Images and fonts drawn onto previews
+//!
+//! Deployments can rebrand the previews by placing replacements into the directory in `PREVIEW_ASSETS_DIR`.
+//! Missing or invalid replacements fall back to the assets embedded into the binary.
+//! If even those can not be decoded, a [`placeholder`] (or a fallback of the asset's own) is drawn instead of failing every request.
+
+use std::path::{Path, PathBuf};
+
+use ab_glyph::FontRef;
+use tracing::{error, info, warn};
+
+/// Replacements larger than this are rejected, as they would be huge compared to the previews
+const MAX_ASSET_SIZE: u32 = 2000;
+/// Width and height of the [`placeholder`]
+const PLACEHOLDER_SIZE: u32 = 64;
+
+/// The directory in which replacements for the embedded assets are looked up
+pub fn asset_dir() -> Option<PathBuf> {
+    std::env::var_os("PREVIEW_ASSETS_DIR").map(PathBuf::from)
+}
+
+/// Loads the image `name` from `dir`, falling back to the `embedded` image
+pub fn load_image(dir: Option<&Path>, name: &str, embedded: &[u8]) -> image::DynamicImage {
+    load_image_or(dir, name, embedded, placeholder)
+}
+
+/// Like [`load_image`], with `fallback` drawing the image if not even the `embedded` one can be decoded
+pub fn load_image_or(
+    dir: Option<&Path>,
+    name: &str,
+    embedded: &[u8],
+    fallback: impl FnOnce() -> image::DynamicImage,
+) -> image::DynamicImage {
+    load_optional_image(dir, name).unwrap_or_else(|| match image::load_from_memory(embedded) {
+        Ok(img) => img,
+        Err(e) => {
+            error!(asset = name, error = ?e, "could not decode the embedded asset, drawing a fallback");
+            fallback()
+        }
+    })
+}
+
+/// A plain rectangle in our blue, standing in for assets which can not be decoded
+///
+/// Previews with a placeholder look off, but serving them beats crashing on every request
+fn placeholder() -> image::DynamicImage {
+    let blue = image::Rgba([0, 101, 189, 255]);
+    image::RgbaImage::from_pixel(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, blue).into()
+}
+
+/// Loads the image `name` from `dir`, for assets which have no embedded version
+pub fn load_optional_image(dir: Option<&Path>, name: &str) -> Option<image::DynamicImage> {
+    let path = dir?.join(name);
+    if !path.exists() {
+        // only some of the assets have to be replaced
+        return None;
+    }
+    match read_image(&path) {
+        Ok(img) => {
+            info!(
+                asset = name,
+                ?path,
+                "using the asset from the assets directory"
+            );
+            Some(img)
+        }
+        Err(e) => {
+            warn!(asset = name, ?path, error = ?e, "could not use the asset");
+            None
+        }
+    }
+}
+
+/// Loads `name`, the version of the asset `base` with twice its resolution, from `dir`, falling back to the `embedded` one
+///
+/// If `dir` replaces `base` without providing `name`, there is no such version, as the embedded one would show the wrong artwork
+pub fn load_double_image(
+    dir: Option<&Path>,
+    base: &str,
+    name: &str,
+    embedded: &[u8],
+) -> Option<image::DynamicImage> {
+    if let Some(img) = load_optional_image(dir, name) {
+        return Some(img);
+    }
+    if dir.is_some_and(|dir| dir.join(base).exists()) {
+        info!(
+            asset = name,
+            base, "the assets directory only replaces the normal version, upscaling it instead"
+        );
+        return None;
+    }
+    match image::load_from_memory(embedded) {
+        Ok(img) => Some(img),
+        Err(e) => {
+            error!(asset = name, error = ?e, "could not decode the embedded asset, upscaling the normal version instead");
+            None
+        }
+    }
+}
+
+fn read_image(path: &Path) -> anyhow::Result<image::DynamicImage> {
+    let img = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?;
+    let size_range = 1..=MAX_ASSET_SIZE;
+    if !size_range.contains(&img.width()) || !size_range.contains(&img.height()) {
+        anyhow::bail!(
+            "{width}x{height}px is not allowed. Assets have to be between 1 and {MAX_ASSET_SIZE}px",
+            width = img.width(),
+            height = img.height()
+        );
+    }
+    Ok(img)
+}
+
+/// Loads the font `name` from `dir`, falling back to the `embedded` font
+///
+/// Fonts are loaded once for the lifetime of the process, so the loaded font is leaked for the shaper to borrow it
+pub fn load_font(dir: Option<&Path>, name: &str, embedded: &'static [u8]) -> &'static [u8] {
+    if let Some(path) = dir.map(|dir| dir.join(name)) {
+        let font = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| match FontRef::try_from_slice(&bytes) {
+                Ok(_) => Ok(bytes),
+                Err(e) => Err(anyhow::anyhow!("{e}")),
+            });
+        match font {
+            Ok(font) => {
+                info!(
+                    asset = name,
+                    ?path,
+                    "using the font from the assets directory"
+                );
+                return Vec::leak(font);
+            }
+            Err(e) => {
+                warn!(asset = name, ?path, error = ?e, "could not use the font, using the embedded one");
+            }
+        }
+    }
+    embedded
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const EMBEDDED_LOGO: &[u8] = include_bytes!("../routes/locations/static/logo.png");
+
+    #[test]
+    fn assets_can_be_overridden() {
+        let dir = tempfile::tempdir().unwrap();
+        let replacement = image::RgbaImage::from_pixel(40, 20, image::Rgba([255, 0, 0, 255]));
+        replacement.save(dir.path().join("logo.png")).unwrap();
+
+        let logo = load_image(Some(dir.path()), "logo.png", EMBEDDED_LOGO);
+        assert_eq!(logo.into_rgba8(), replacement);
+        // assets which are not replaced stay embedded
+        let embedded = image::load_from_memory(EMBEDDED_LOGO).unwrap();
+        let pin = load_image(Some(dir.path()), "pin.png", EMBEDDED_LOGO);
+        assert_eq!(pin, embedded);
+        assert_eq!(load_image(None, "logo.png", EMBEDDED_LOGO), embedded);
+    }
+
+    #[test]
+    fn optional_assets_are_only_loaded_if_present() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(load_optional_image(None, "logo@2x.png"), None);
+        assert_eq!(load_optional_image(Some(dir.path()), "logo@2x.png"), None);
+        let double = image::RgbaImage::from_pixel(80, 40, image::Rgba([255, 0, 0, 255]));
+        double.save(dir.path().join("logo@2x.png")).unwrap();
+        let loaded = load_optional_image(Some(dir.path()), "logo@2x.png").unwrap();
+        assert_eq!(loaded.into_rgba8(), double);
+    }
+
+    #[test]
+    fn double_assets_match_the_replaced_ones() {
+        let load =
+            |dir: Option<&Path>| load_double_image(dir, "logo.png", "logo@2x.png", EMBEDDED_LOGO);
+        let embedded = image::load_from_memory(EMBEDDED_LOGO).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(load(None), Some(embedded.clone()));
+        assert_eq!(load(Some(dir.path())), Some(embedded));
+
+        // a replaced logo without a double => the replacement is upscaled instead of drawing the embedded double
+        let replacement = image::RgbaImage::from_pixel(40, 20, image::Rgba([255, 0, 0, 255]));
+        replacement.save(dir.path().join("logo.png")).unwrap();
+        assert_eq!(load(Some(dir.path())), None);
+
+        let double = image::RgbaImage::from_pixel(80, 40, image::Rgba([255, 0, 0, 255]));
+        double.save(dir.path().join("logo@2x.png")).unwrap();
+        assert_eq!(load(Some(dir.path())).unwrap().into_rgba8(), double);
+    }
+
+    #[test]
+    fn invalid_assets_are_not_used() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("logo.png"), b"not an image").unwrap();
+        image::RgbaImage::new(MAX_ASSET_SIZE + 1, 1)
+            .save(dir.path().join("pin.png"))
+            .unwrap();
+        let embedded = image::load_from_memory(EMBEDDED_LOGO).unwrap();
+        assert_eq!(
+            load_image(Some(dir.path()), "logo.png", EMBEDDED_LOGO),
+            embedded
+        );
+        assert_eq!(
+            load_image(Some(dir.path()), "pin.png", EMBEDDED_LOGO),
+            embedded
+        );
+
+        std::fs::write(dir.path().join("font.ttf"), b"not a font").unwrap();
+        let embedded = include_bytes!("font/Cantarell-Regular.ttf");
+        let font = load_font(Some(dir.path()), "font.ttf", embedded);
+        assert_eq!(font, embedded);
+    }
+
+    #[test]
+    fn undecodable_assets_are_replaced_by_a_placeholder() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("logo.png"), b"not an image").unwrap();
+        let corrupt = b"\x89PNG\r\n\x1a\ntruncated";
+        for dir in [None, Some(dir.path())] {
+            let logo = load_image(dir, "logo.png", corrupt);
+            assert_eq!(logo, placeholder());
+            assert!(logo.width() > 0 && logo.height() > 0);
+        }
+    }
+}
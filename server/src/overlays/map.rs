@@ -1,15 +1,26 @@
 use std::fmt;
 use std::ops::Range;
 
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::StreamExt;
+use image::Rgba;
+use imageproc::point::Point;
 use tracing::warn;
 
-use crate::external::download_map_image::MapImageDownloadTask;
+use crate::external::download_map_image::{MapImageDownloadTask, TileServer, TileStyle};
 
 pub struct OverlayMapTask {
     pub x: f64,
     pub y: f64,
     pub z: u32,
+    /// pixels at the bottom of the image, which are covered by something else and thus not part of the map
+    bottom_bar_height: u32,
+    /// pixels by which the point of `x`/`y` is shown above the center of the map
+    vertical_padding: u32,
+    pub style: TileStyle,
+    /// outline as `(lat, lon)` points, which is highlighted on the map
+    footprint: Option<Vec<(f64, f64)>>,
+    /// width and height of the tiles in pixels, see [`OverlayMapTask::with_tile_size`]
+    tile_size: u32,
 }
 
 impl fmt::Debug for OverlayMapTask {
@@ -18,33 +29,143 @@ impl fmt::Debug for OverlayMapTask {
             .field(&self.x)
             .field(&self.y)
             .field(&self.z)
+            .field(&self.bottom_bar_height)
+            .field(&self.vertical_padding)
+            .field(&self.style)
+            .field(&self.footprint.as_ref().map(Vec::len))
+            .field(&self.tile_size)
             .finish()
     }
 }
 
-const POSSIBLE_INDEX_RANGE: Range<u32> = 0..7;
+/// half the height of the pin => the pin itself is in the center of the map instead of its tip
+const DEFAULT_VERTICAL_PADDING: u32 = 50;
+
+/// Width and height of the "retina" tiles of our tileserver, which the zoom levels refer to
+pub const DEFAULT_TILE_SIZE: u32 = 512;
+
+/// covers maps of up to 4000px (2000px at [`OverlayMapTask::with_scale`] 2) in each direction
+const GRID_PIXELS: u32 = 9 * DEFAULT_TILE_SIZE;
+
+/// indexes of the grid of tiles of `tile_size`, which is centered around the point of interest
+fn index_range(tile_size: u32) -> Range<u32> {
+    0..GRID_PIXELS / tile_size
+}
 
 impl OverlayMapTask {
-    pub fn new(r#type: &str, lat: f64, lon: f64) -> Self {
-        let zoom = match r#type {
-            "campus" => 14,
-            "area" | "site" => 15,
-            "building" | "joined_building" => 16,
-            "virtual_room" | "room" | "poi" => 17,
-            entry => {
-                warn!(
-                    ?entry,
-                    "map generation encountered an type. Assuming it to be a building",
-                );
-                16
-            }
-        };
+    /// `zoom` overrides the zoom level, which is otherwise chosen based on the `type`
+    pub fn new(r#type: &str, lat: f64, lon: f64, zoom: Option<u32>) -> Self {
+        let zoom = zoom.unwrap_or_else(|| default_zoom(r#type));
         let (x, y, z) = lat_lon_z_to_xyz(lat, lon, zoom);
-        Self { x, y, z }
+        Self {
+            x,
+            y,
+            z,
+            bottom_bar_height: 125,
+            vertical_padding: DEFAULT_VERTICAL_PADDING,
+            style: TileStyle::default(),
+            footprint: None,
+            tile_size: DEFAULT_TILE_SIZE,
+        }
     }
 
-    #[tracing::instrument(skip(img))]
-    pub async fn draw_onto(&self, img: &mut image::RgbaImage) -> bool {
+    pub fn with_bottom_bar_height(self, bottom_bar_height: u32) -> Self {
+        Self {
+            bottom_bar_height,
+            ..self
+        }
+    }
+
+    /// Shows the point of interest `vertical_padding` pixels above the center of the map.
+    ///
+    /// This keeps whatever is drawn above the point (like the pin) clear of the bottom of the map.
+    pub fn with_vertical_padding(self, vertical_padding: u32) -> Self {
+        Self {
+            vertical_padding,
+            ..self
+        }
+    }
+
+    pub fn with_style(self, style: TileStyle) -> Self {
+        Self { style, ..self }
+    }
+
+    pub fn with_footprint(self, footprint: Option<Vec<(f64, f64)>>) -> Self {
+        Self { footprint, ..self }
+    }
+
+    /// Shows the same area on an image which is `scale` times as large, by using tiles of a higher zoom level
+    ///
+    /// `scale` has to be a power of two, as every zoom level doubles the resolution
+    pub fn with_scale(self, scale: u32) -> Self {
+        debug_assert!(scale.is_power_of_two(), "{scale} is not a power of two");
+        let levels = scale.max(1).ilog2();
+        let factor = f64::from(2_u32.pow(levels));
+        Self {
+            x: self.x * factor,
+            y: self.y * factor,
+            z: self.z + levels,
+            vertical_padding: self.vertical_padding * 2_u32.pow(levels),
+            ..self
+        }
+    }
+
+    /// Composites the map from tiles of `tile_size` instead of [`DEFAULT_TILE_SIZE`], e.g. 256px
+    ///
+    /// The map keeps showing the same area at the same size => smaller tiles are fetched from a higher zoom level.
+    /// `tile_size` has to be a power of two up to [`DEFAULT_TILE_SIZE`], as every zoom level doubles the resolution
+    pub fn with_tile_size(self, tile_size: u32) -> Self {
+        debug_assert!(
+            tile_size.is_power_of_two() && tile_size <= DEFAULT_TILE_SIZE,
+            "{tile_size} is no supported tile size"
+        );
+        let levels = (DEFAULT_TILE_SIZE / tile_size.clamp(1, DEFAULT_TILE_SIZE)).ilog2();
+        let factor = f64::from(2_u32.pow(levels));
+        Self {
+            x: self.x * factor,
+            y: self.y * factor,
+            z: self.z + levels,
+            tile_size: DEFAULT_TILE_SIZE / 2_u32.pow(levels),
+            ..self
+        }
+    }
+
+    /// Centers the map on `points` (as `(lat, lon)`), zooming out until all of them are at least `padding` pixels
+    /// away from the edges of a map of `map_size`
+    ///
+    /// The points are centered on the map itself, as the vertical padding is already covered by `padding`.
+    /// With less than two points, there is nothing to fit => the map stays as it is
+    pub fn fitting(self, points: &[(f64, f64)], (width, height): (u32, u32), padding: u32) -> Self {
+        if points.len() < 2 {
+            return self;
+        }
+        let available_width = f64::from(width.saturating_sub(2 * padding));
+        let available_height = f64::from(height.saturating_sub(2 * padding));
+        let mut z = self.z;
+        loop {
+            let tiles = points
+                .iter()
+                .map(|(lat, lon)| lat_lon_z_to_xyz(*lat, *lon, z))
+                .collect::<Vec<_>>();
+            let (min_x, max_x) = min_max(tiles.iter().map(|(x, _, _)| *x));
+            let (min_y, max_y) = min_max(tiles.iter().map(|(_, y, _)| *y));
+            let tile_size = f64::from(self.tile_size);
+            let fits = (max_x - min_x) * tile_size <= available_width
+                && (max_y - min_y) * tile_size <= available_height;
+            if fits || z == 0 {
+                return Self {
+                    x: (min_x + max_x) / 2.0,
+                    y: (min_y + max_y) / 2.0 - f64::from(self.vertical_padding) / tile_size,
+                    z,
+                    ..self
+                };
+            }
+            z -= 1;
+        }
+    }
+
+    #[tracing::instrument(name = "fetch_tiles", skip(tiles, img))]
+    pub async fn draw_onto(&self, tiles: &TileServer, img: &mut image::RgbaImage) -> bool {
         // coordinate system is centered around the center of the image
         // around this center there is a 5*5 grid of tiles
         // -------------------------------
@@ -54,40 +175,158 @@ impl OverlayMapTask {
         // -------------------------------
         // we can now filter for "is on the image" and append them to a work queue
 
-        let x_pixels = (512.0 * (self.x - self.x.floor())) as u32;
-        let y_pixels = (512.0 * (self.y - self.y.floor())) as u32;
-        let (x_img_coords, y_img_coords) = center_to_top_left_coordinates(img, x_pixels, y_pixels);
-        // is_in_range is quite cheap => we over-check this one to cope with different image formats
-        let mut work_queue = FuturesUnordered::new();
-        for index_x in POSSIBLE_INDEX_RANGE.clone() {
-            for index_y in POSSIBLE_INDEX_RANGE.clone() {
-                if is_on_image(img, (x_img_coords, y_img_coords), (index_x, index_y)) {
-                    let offset_x = (index_x as i32) - ((POSSIBLE_INDEX_RANGE.end / 2) as i32);
-                    let offset_y = (index_y as i32) - ((POSSIBLE_INDEX_RANGE.end / 2) as i32);
-                    work_queue.push(
-                        MapImageDownloadTask::from(self)
-                            .offset_by(offset_x, offset_y)
-                            .with_index(index_x, index_y)
-                            .fulfill(),
-                    );
-                }
-            }
-        }
-        // draw the tiles onto the image after receiving them
-        while let Some(res) = work_queue.next().await {
+        let (x, y) = self.center();
+        let tile_size = self.tile_size;
+        let x_pixels = (f64::from(tile_size) * (x - x.floor())) as u32;
+        let y_pixels = (f64::from(tile_size) * (y - y.floor())) as u32;
+        let map_size = self.map_size(img);
+        let (x_img_coords, y_img_coords) =
+            center_to_top_left_coordinates(map_size, x_pixels, y_pixels, tile_size);
+        let center_index = (index_range(tile_size).end / 2) as i32;
+        let work_queue = visible_tiles(map_size, (x_img_coords, y_img_coords), tile_size)
+            .map(|(index_x, index_y)| {
+                let offset_x = (index_x as i32) - center_index;
+                let offset_y = (index_y as i32) - center_index;
+                MapImageDownloadTask::from(self)
+                    .offset_by(offset_x, offset_y)
+                    .with_index(index_x, index_y)
+            })
+            .collect::<Vec<_>>();
+        // bounded to not overwhelm the tileserver with the many tiles of bigger formats
+        let mut downloads = futures::stream::iter(work_queue)
+            .map(|task| task.fulfill(tiles))
+            .buffer_unordered(tiles.max_concurrent_downloads());
+        while let Some(res) = downloads.next().await {
             match res {
-                Some(((x_index, y_index), tile_img)) => {
-                    let x = x_index as i64 * 512 - (x_img_coords as i64);
-                    let y = y_index as i64 * 512 - (y_img_coords as i64);
-                    image::imageops::overlay(img, &tile_img, x, y);
+                // composited right away => only the tiles in flight are held in memory, not the whole grid
+                Some((index, tile)) => {
+                    draw_tile(img, index, &tile, (x_img_coords, y_img_coords), tile_size)
                 }
                 None => {
                     return false;
                 }
             }
         }
+        if let Some(footprint) = &self.footprint {
+            let outline = footprint
+                .iter()
+                .map(|(lat, lon)| self.project(map_size, *lat, *lon))
+                .collect::<Vec<_>>();
+            draw_footprint(img, &outline);
+        }
         true
     }
+
+    /// Upper bound of how many tiles of `tile_size` [`Self::draw_onto`] fetches for a map of `map_size`, wherever it is centered
+    ///
+    /// Tiles have the same size at every zoom level => only the size of the map matters
+    pub fn max_tile_count((map_width, map_height): (u32, u32), tile_size: u32) -> u32 {
+        // a tile only partially on the map at each edge
+        let tiles_along = |pixels: u32| pixels / tile_size + 2;
+        tiles_along(map_width) * tiles_along(map_height)
+    }
+
+    /// the part of `img` which is covered by the map
+    pub fn map_size(&self, img: &image::RgbaImage) -> (u32, u32) {
+        (img.width(), img.height() - self.bottom_bar_height)
+    }
+
+    /// tile coordinates of the center of the map, which is below the point of interest by the vertical padding
+    pub fn center(&self) -> (f64, f64) {
+        (
+            self.x,
+            self.y + f64::from(self.vertical_padding) / f64::from(self.tile_size),
+        )
+    }
+
+    /// pixel coordinates of `lat`/`lon` on a map of `map_size`, which is centered around this task
+    pub fn project(&self, (map_width, map_height): (u32, u32), lat: f64, lon: f64) -> (f32, f32) {
+        let (x, y, _) = lat_lon_z_to_xyz(lat, lon, self.z);
+        let (center_x, center_y) = self.center();
+        let tile_size = f64::from(self.tile_size);
+        let x_pixel = f64::from(map_width) / 2.0 + (x - center_x) * tile_size;
+        let y_pixel = f64::from(map_height) / 2.0 + (y - center_y) * tile_size;
+        (x_pixel as f32, y_pixel as f32)
+    }
+
+    /// how many meters a pixel at the point of interest covers
+    pub fn meters_per_pixel(&self) -> f64 {
+        let n = 2_u32.pow(self.z) as f64;
+        let lat_rad = (std::f64::consts::PI * (1.0 - 2.0 * self.y / n))
+            .sinh()
+            .atan();
+        EARTH_CIRCUMFERENCE * lat_rad.cos() / (f64::from(self.tile_size) * n)
+    }
+}
+
+/// equatorial circumference in meters, as used by the web mercator projection
+const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
+
+const FOOTPRINT_FILL: Rgba<u8> = Rgba([0, 101, 189, 64]);
+const FOOTPRINT_OUTLINE: Rgba<u8> = Rgba([0, 101, 189, 255]);
+
+/// highlights the polygon `outline` (in pixels) with a semi-transparent fill
+fn draw_footprint(img: &mut image::RgbaImage, outline: &[(f32, f32)]) {
+    let mut points = outline
+        .iter()
+        .map(|(x, y)| Point::new(x.round() as i32, y.round() as i32))
+        .collect::<Vec<_>>();
+    // imageproc requires open polygons
+    points.dedup();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    if points.len() < 3 {
+        return;
+    }
+    let mut layer = image::RgbaImage::new(img.width(), img.height());
+    imageproc::drawing::draw_polygon_mut(&mut layer, &points, FOOTPRINT_FILL);
+    let outline = points
+        .iter()
+        .map(|p| Point::new(p.x as f32, p.y as f32))
+        .collect::<Vec<_>>();
+    imageproc::drawing::draw_hollow_polygon_mut(&mut layer, &outline, FOOTPRINT_OUTLINE);
+    image::imageops::overlay(img, &layer, 0, 0);
+}
+
+/// Draws the tile at `index` of the grid of tiles of `tile_size`.
+///
+/// Tiles do not overlap => they can be drawn in any order
+fn draw_tile(
+    img: &mut image::RgbaImage,
+    (x_index, y_index): (u32, u32),
+    tile: &image::DynamicImage,
+    (x_img_coords, y_img_coords): (u32, u32),
+    tile_size: u32,
+) {
+    let x = x_index as i64 * i64::from(tile_size) - (x_img_coords as i64);
+    let y = y_index as i64 * i64::from(tile_size) - (y_img_coords as i64);
+    image::imageops::overlay(img, tile, x, y);
+}
+
+/// The zoom level at which a location of this `type` is shown in full, while still giving some context
+///
+/// Rooms thus get a tighter zoom than buildings, which get a tighter zoom than whole campuses.
+fn default_zoom(r#type: &str) -> u32 {
+    match r#type {
+        "campus" => 14,
+        "area" | "site" => 15,
+        "building" | "joined_building" => 16,
+        "virtual_room" | "room" | "poi" => 17,
+        entry => {
+            warn!(
+                ?entry,
+                "map generation encountered an unknown type. Assuming it to be a building",
+            );
+            16
+        }
+    }
+}
+
+fn min_max(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+        (min.min(value), max.max(value))
+    })
 }
 
 fn lat_lon_z_to_xyz(lat_deg: f64, lon_deg: f64, zoom: u32) -> (f64, f64, u32) {
@@ -101,25 +340,42 @@ fn lat_lon_z_to_xyz(lat_deg: f64, lon_deg: f64, zoom: u32) -> (f64, f64, u32) {
 /// The center coordinates are usefully for orienting ourselves in one tile
 /// For drawing them, top left is better
 fn center_to_top_left_coordinates(
-    img: &image::RgbaImage,
+    (map_width, map_height): (u32, u32),
     x_pixels: u32,
     y_pixels: u32,
+    tile_size: u32,
 ) -> (u32, u32) {
-    let y_to_img_border = 512 * (POSSIBLE_INDEX_RANGE.end / 2) + y_pixels;
-    let y_img_coords = y_to_img_border - (img.height() - 125) / 2;
-    let x_to_img_border = 512 * (POSSIBLE_INDEX_RANGE.end / 2) + x_pixels;
-    let x_img_coords = x_to_img_border - img.width() / 2;
+    let center_index = index_range(tile_size).end / 2;
+    let y_to_img_border = tile_size * center_index + y_pixels;
+    let y_img_coords = y_to_img_border - map_height / 2;
+    let x_to_img_border = tile_size * center_index + x_pixels;
+    let x_img_coords = x_to_img_border - map_width / 2;
     (x_img_coords, y_img_coords)
 }
 
+/// indexes of the tiles in the [`index_range`], which are at least partially on the map
+///
+/// is_on_image is quite cheap => we over-check this one to cope with different image formats
+fn visible_tiles(
+    map_size: (u32, u32),
+    top_left: (u32, u32),
+    tile_size: u32,
+) -> impl Iterator<Item = (u32, u32)> {
+    index_range(tile_size)
+        .flat_map(move |index_x| index_range(tile_size).map(move |index_y| (index_x, index_y)))
+        .filter(move |index| is_on_image(map_size, top_left, *index, tile_size))
+}
+
 fn is_on_image(
-    img: &image::RgbaImage,
+    (map_width, map_height): (u32, u32),
     (x_pixel, y_pixel): (u32, u32),
     (x_index, y_index): (u32, u32),
+    tile_size: u32,
 ) -> bool {
-    let x_in_range = (x_index + 1) * 512 >= x_pixel && x_index * 512 <= x_pixel + img.width();
+    let x_in_range =
+        (x_index + 1) * tile_size >= x_pixel && x_index * tile_size <= x_pixel + map_width;
     let y_in_range =
-        (y_index + 1) * 512 >= y_pixel && y_index * 512 <= y_pixel + (img.height() - 125);
+        (y_index + 1) * tile_size >= y_pixel && y_index * tile_size <= y_pixel + map_height;
     x_in_range && y_in_range
 }
 
@@ -129,6 +385,168 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_default_zoom() {
+        assert!(default_zoom("room") > default_zoom("building"));
+        assert!(default_zoom("building") > default_zoom("area"));
+        assert!(default_zoom("area") > default_zoom("campus"));
+        assert_eq!(default_zoom("room"), default_zoom("virtual_room"));
+        assert_eq!(default_zoom("building"), default_zoom("joined_building"));
+        // unknown types are assumed to be buildings
+        assert_eq!(default_zoom("spaceship"), default_zoom("building"));
+    }
+
+    #[test]
+    fn viewport_fits_all_points() {
+        // three buildings on the garching campus, further apart than the default zoom of rooms can show
+        let points = [(48.2626, 11.6679), (48.2655, 11.6712), (48.2683, 11.6681)];
+        let map_size = (1200, 505);
+        let padding = 100;
+        let centered = OverlayMapTask::new("room", points[0].0, points[0].1, None);
+        let fitted = OverlayMapTask::new("room", points[0].0, points[0].1, None)
+            .fitting(&points, map_size, padding);
+        assert!(fitted.z < centered.z, "the map is zoomed out");
+        for (lat, lon) in points {
+            let (x, y) = fitted.project(map_size, lat, lon);
+            assert!(
+                (padding as f32..=(1200 - padding) as f32).contains(&x),
+                "{x}"
+            );
+            assert!(
+                (padding as f32..=(505 - padding) as f32).contains(&y),
+                "{y}"
+            );
+        }
+        // one zoom level further in, the points would not fit
+        let (_, top, _) = lat_lon_z_to_xyz(points[2].0, points[2].1, fitted.z + 1);
+        let (_, bottom, _) = lat_lon_z_to_xyz(points[0].0, points[0].1, fitted.z + 1);
+        assert!((bottom - top) * 512.0 > f64::from(505 - 2 * padding));
+
+        // a single point keeps the map as it is
+        let single = OverlayMapTask::new("room", points[0].0, points[0].1, None).fitting(
+            &points[..1],
+            map_size,
+            padding,
+        );
+        assert_eq!(
+            (single.x, single.y, single.z),
+            (centered.x, centered.y, centered.z)
+        );
+    }
+
+    #[test]
+    fn footprint_is_highlighted() {
+        let white = Rgba([255, 255, 255, 255]);
+        let mut img = image::RgbaImage::from_pixel(300, 300, white);
+        let square = [
+            (100.0, 100.0),
+            (200.0, 100.0),
+            (200.0, 200.0),
+            (100.0, 200.0),
+            (100.0, 100.0),
+        ];
+        draw_footprint(&mut img, &square);
+        // outside stays untouched
+        for (x, y) in [(50, 50), (250, 150), (150, 250), (99, 99)] {
+            assert_eq!(img.get_pixel(x, y), &white, "pixel {x}/{y}");
+        }
+        // inside is tinted, but the map stays visible
+        for (x, y) in [(150, 150), (110, 190), (190, 110)] {
+            let pixel = img.get_pixel(x, y);
+            assert!(
+                pixel.0[0] < 255 && pixel.0[0] > 100,
+                "pixel {x}/{y}: {pixel:?}"
+            );
+            assert!(pixel.0[2] > pixel.0[0], "pixel {x}/{y}: {pixel:?}");
+        }
+        // the outline is opaque
+        for (x, y) in [(100, 150), (150, 100), (200, 150), (150, 200)] {
+            assert_eq!(img.get_pixel(x, y), &FOOTPRINT_OUTLINE, "pixel {x}/{y}");
+        }
+    }
+
+    #[test]
+    fn degenerate_footprints_are_ignored() {
+        let mut img = image::RgbaImage::new(10, 10);
+        draw_footprint(&mut img, &[]);
+        draw_footprint(&mut img, &[(1.0, 1.0), (5.0, 5.0), (1.0, 1.0)]);
+        assert!(img.pixels().all(|p| p.0[3] == 0));
+    }
+
+    #[test]
+    fn center_is_projected_onto_the_center() {
+        let task = OverlayMapTask::new("building", 48.14, 11.58, None).with_vertical_padding(0);
+        let (x, y) = task.project((1200, 505), 48.14, 11.58);
+        assert!((x - 600.0).abs() < 0.01, "{x}");
+        assert!((y - 252.5).abs() < 0.01, "{y}");
+        // north is up, east is right
+        let (x, y) = task.project((1200, 505), 48.141, 11.581);
+        assert!(x > 600.0 && y < 252.5);
+    }
+
+    #[test]
+    fn padding_lifts_the_point_of_interest() {
+        let padded = |padding| {
+            OverlayMapTask::new("room", 48.14, 11.58, Some(17)).with_vertical_padding(padding)
+        };
+        let (x, y) = padded(0).center();
+        let (shifted_x, shifted_y) = padded(100).center();
+        // the map is centered further south => the point is shown above the center
+        assert_eq!(shifted_x, x);
+        assert!((shifted_y - y - 100.0 / 512.0).abs() < 1e-9);
+        let (_, center_y) = padded(0).project((1200, 505), 48.14, 11.58);
+        let (_, lifted_y) = padded(100).project((1200, 505), 48.14, 11.58);
+        assert!((center_y - lifted_y - 100.0).abs() < 0.01, "{lifted_y}");
+        // more padding lifts the point further
+        let (_, default_y) =
+            OverlayMapTask::new("room", 48.14, 11.58, Some(17)).project((1200, 505), 48.14, 11.58);
+        assert!(lifted_y < default_y && default_y < center_y);
+        // the padding covers the same area on larger images
+        let (_, scaled_y) = padded(100).with_scale(2).center();
+        let (_, unpadded_y) = padded(0).with_scale(2).center();
+        assert!((scaled_y - unpadded_y - 200.0 / 512.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn projection_is_offset_by_tiles() {
+        let task = OverlayMapTask::new("room", 48.14, 11.58, Some(17)).with_vertical_padding(0);
+        assert_eq!(task.z, 17);
+        let map_size = task.map_size(&image::RgbaImage::new(1200, 630));
+        assert_eq!(map_size, (1200, 505));
+        // one tile is 512px wide
+        let tile_width = 360.0 / 2_f64.powi(17);
+        let (x, y) = task.project(map_size, 48.14, 11.58 + tile_width);
+        assert!((x - (600.0 + 512.0)).abs() < 0.01, "{x}");
+        assert!((y - 252.5).abs() < 0.01, "{y}");
+        let (x, _) = task.project(map_size, 48.14, 11.58 - tile_width / 4.0);
+        assert!((x - (600.0 - 128.0)).abs() < 0.01, "{x}");
+    }
+
+    #[test]
+    fn scale_zooms_in_on_the_same_location() {
+        let single = OverlayMapTask::new("room", 48.14, 11.58, Some(17));
+        let double = OverlayMapTask::new("room", 48.14, 11.58, Some(17)).with_scale(2);
+        let zoomed = OverlayMapTask::new("room", 48.14, 11.58, Some(18));
+        assert_eq!(double.z, 18);
+        assert!((double.x - zoomed.x).abs() < 1e-9);
+        assert!((double.y - zoomed.y).abs() < 1e-9);
+        assert!((double.meters_per_pixel() * 2.0 - single.meters_per_pixel()).abs() < 1e-9);
+        assert_eq!(single.with_scale(1).z, 17);
+    }
+
+    #[test]
+    fn meters_per_pixel_halves_per_zoom_level() {
+        let at_zoom =
+            |zoom| OverlayMapTask::new("room", 48.14, 11.58, Some(zoom)).meters_per_pixel();
+        // 40075016.686 * cos(48.14°) / (512 * 2^17)
+        assert!((at_zoom(17) - 0.3986).abs() < 0.001, "{}", at_zoom(17));
+        assert!((at_zoom(16) - 2.0 * at_zoom(17)).abs() < 1e-9);
+        assert!((at_zoom(14) - 8.0 * at_zoom(17)).abs() < 1e-9);
+        // closer to the poles, a pixel covers less ground
+        let north = OverlayMapTask::new("room", 60.0, 11.58, Some(17)).meters_per_pixel();
+        assert!(north < at_zoom(17));
+    }
+
     #[test]
     fn test_lat_lon_z_to_xyz() {
         let (x, y, _) = lat_lon_z_to_xyz(52.520_008, 13.404_954, 17);
@@ -158,20 +576,240 @@ mod tests {
         expected_x: (u32, u32),
         expected_y: (u32, u32),
     ) {
-        let img = image::RgbaImage::new(1200, 630);
+        let map_size = (1200, 630 - 125);
         for x in 0..10 {
             for y in 0..10 {
                 let (x_min, x_max) = expected_x;
                 let (y_min, y_max) = expected_y;
                 let expected_result = x <= x_max && x >= x_min && y <= y_max && y >= y_min;
                 assert_eq!(
-                    is_on_image(&img, (x_pixels, y_pixels), (x, y)),
+                    is_on_image(map_size, (x_pixels, y_pixels), (x, y), DEFAULT_TILE_SIZE),
                     expected_result
                 );
             }
         }
     }
 
+    #[test]
+    fn tiles_are_drawn_at_their_offsets() {
+        let colors = |x: u32, y: u32| image::Rgba([x as u8 * 50, y as u8 * 50, 0, 255]);
+        let mut tiles = Vec::new();
+        for x in 0..3 {
+            for y in 0..2 {
+                let tile = image::RgbaImage::from_pixel(512, 512, colors(x, y));
+                tiles.push(((x, y), image::DynamicImage::ImageRgba8(tile)));
+            }
+        }
+        // completion order is arbitrary
+        tiles.reverse();
+        tiles.swap(1, 4);
+        let mut img = image::RgbaImage::new(1200, 630);
+        for (index, tile) in &tiles {
+            draw_tile(&mut img, *index, tile, (100, 200), DEFAULT_TILE_SIZE);
+        }
+        for (x, y) in [(0, 0), (411, 311), (412, 312), (1199, 629), (923, 100)] {
+            let expected = colors((x + 100) / 512, (y + 200) / 512);
+            assert_eq!(img.get_pixel(x, y), &expected, "pixel {x}/{y}");
+        }
+    }
+
+    #[test]
+    fn streamed_tiles_match_collected_ones() {
+        let mut tiles = Vec::new();
+        for x in 0..4 {
+            for y in 0..3 {
+                let tile = image::RgbaImage::from_fn(512, 512, |px, py| {
+                    image::Rgba([
+                        (px + x * 7) as u8,
+                        (py + y * 13) as u8,
+                        (x * 3 + y) as u8,
+                        255,
+                    ])
+                });
+                tiles.push(((x, y), image::DynamicImage::ImageRgba8(tile)));
+            }
+        }
+        let coords = (300, 400);
+        // previously, all tiles were collected and then composited in the order of the grid
+        let mut collected = image::RgbaImage::new(1200, 1200);
+        let mut sorted = tiles.clone();
+        sorted.sort_unstable_by_key(|(index, _)| *index);
+        for ((x, y), tile) in &sorted {
+            let (x, y) = (*x as i64 * 512 - 300, *y as i64 * 512 - 400);
+            image::imageops::overlay(&mut collected, tile, x, y);
+        }
+        // tiles arrive in arbitrary order
+        tiles.reverse();
+        tiles.swap(2, 7);
+        let mut streamed = image::RgbaImage::new(1200, 1200);
+        for (index, tile) in &tiles {
+            draw_tile(&mut streamed, *index, tile, coords, DEFAULT_TILE_SIZE);
+        }
+        assert!(streamed == collected, "streamed composite differs");
+    }
+
+    #[actix_web::test]
+    async fn tile_downloads_are_bounded() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use crate::setup::tests::MockTileServer;
+
+        let mut tile = std::io::Cursor::new(Vec::new());
+        image::RgbaImage::from_fn(512, 512, |x, y| image::Rgba([x as u8, y as u8, 0, 255]))
+            .write_to(&mut tile, image::ImageFormat::Png)
+            .unwrap();
+        let tile = tile.into_inner();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let mock = {
+            let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+            MockTileServer::new(move |_| {
+                let (in_flight, max_in_flight, tile) =
+                    (in_flight.clone(), max_in_flight.clone(), tile.clone());
+                async move {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    actix_web::HttpResponse::Ok().body(tile)
+                }
+            })
+            .await
+        };
+        let tiles = TileServer::mock(&[&mock.url]);
+        let mut img = image::RgbaImage::new(1200, 1200);
+        let drawn = OverlayMapTask::new("room", 48.26842603718826, 11.677995005953209, None)
+            .draw_onto(&tiles, &mut img)
+            .await;
+        assert!(drawn);
+        assert!(mock.requests() > tiles.max_concurrent_downloads());
+        let max_in_flight = max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight <= tiles.max_concurrent_downloads());
+        assert!(max_in_flight > 1, "tiles should be downloaded concurrently");
+    }
+
+    #[test]
+    fn tile_count_is_bounded_wherever_the_map_is_centered() {
+        for map_size in [(1200, 505), (512, 512), (2000, 2000), (4000, 1010)] {
+            let max = OverlayMapTask::max_tile_count(map_size, DEFAULT_TILE_SIZE);
+            let mut most_visible = 0;
+            for offset in (0..512).step_by(16) {
+                let top_left = center_to_top_left_coordinates(
+                    map_size,
+                    offset,
+                    511 - offset,
+                    DEFAULT_TILE_SIZE,
+                );
+                let visible = visible_tiles(map_size, top_left, DEFAULT_TILE_SIZE).count() as u32;
+                assert!(
+                    visible <= max,
+                    "{map_size:?} at {offset}: {visible} > {max}"
+                );
+                most_visible = most_visible.max(visible);
+            }
+            // the bound is not overly pessimistic
+            assert!(
+                most_visible + 4 >= max,
+                "{map_size:?}: {most_visible} vs {max}"
+            );
+        }
+    }
+
+    #[test]
+    fn smaller_tiles_show_the_same_map() {
+        let retina = OverlayMapTask::new("room", 48.14, 11.58, Some(17));
+        let small = OverlayMapTask::new("room", 48.14, 11.58, Some(17)).with_tile_size(256);
+        assert_eq!((small.z, small.tile_size), (18, 256));
+        let map_size = (1200, 505);
+        for (lat, lon) in [(48.14, 11.58), (48.141, 11.583), (48.139, 11.575)] {
+            let (x, y) = retina.project(map_size, lat, lon);
+            let (small_x, small_y) = small.project(map_size, lat, lon);
+            assert!((x - small_x).abs() < 0.01 && (y - small_y).abs() < 0.01);
+        }
+        assert!((retina.meters_per_pixel() - small.meters_per_pixel()).abs() < 1e-9);
+        let (x, y) = retina.center();
+        let (small_x, small_y) = small.center();
+        assert!((small_x - 2.0 * x).abs() < 1e-9 && (small_y - 2.0 * y).abs() < 1e-9);
+        assert_eq!(
+            OverlayMapTask::new("room", 48.14, 11.58, Some(17))
+                .with_tile_size(DEFAULT_TILE_SIZE)
+                .z,
+            17
+        );
+    }
+
+    #[test]
+    fn tile_grid_uses_the_tile_size() {
+        assert_eq!(index_range(DEFAULT_TILE_SIZE), 0..9);
+        assert_eq!(index_range(256), 0..18);
+        // 4 full columns and 1 full row, plus a partial tile at each edge
+        assert_eq!(OverlayMapTask::max_tile_count((1200, 505), 256), 6 * 3);
+        assert_eq!(OverlayMapTask::max_tile_count((1200, 505), 512), 4 * 2);
+        let map_size = (1200, 505);
+        // a map centered on the corner of a tile
+        let top_left = center_to_top_left_coordinates(map_size, 0, 0, 256);
+        assert_eq!(top_left, (9 * 256 - 600, 9 * 256 - 252));
+        let visible = visible_tiles(map_size, top_left, 256).collect::<Vec<_>>();
+        let columns = visible
+            .iter()
+            .map(|(x, _)| *x)
+            .collect::<std::collections::BTreeSet<_>>();
+        let rows = visible
+            .iter()
+            .map(|(_, y)| *y)
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(
+            columns.into_iter().collect::<Vec<_>>(),
+            (6..=11).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            rows.into_iter().collect::<Vec<_>>(),
+            (8..=9).collect::<Vec<_>>()
+        );
+        assert_eq!(visible.len(), 6 * 2);
+        // the tile right of the center starts at the center
+        let mut img = image::RgbaImage::new(1200, 505);
+        let tile = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            256,
+            256,
+            Rgba([255, 0, 0, 255]),
+        ));
+        draw_tile(&mut img, (9, 9), &tile, top_left, 256);
+        assert_eq!(img.get_pixel(600, 252).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(599, 252).0[3], 0);
+        assert_eq!(img.get_pixel(600 + 256, 252).0[3], 0);
+    }
+
+    #[actix_web::test]
+    async fn small_tiles_cover_the_whole_map() {
+        use crate::setup::tests::MockTileServer;
+
+        let mut tile = std::io::Cursor::new(Vec::new());
+        image::RgbaImage::from_pixel(256, 256, Rgba([0, 200, 0, 255]))
+            .write_to(&mut tile, image::ImageFormat::Png)
+            .unwrap();
+        let tile = tile.into_inner();
+        let mock = MockTileServer::new(move |_| {
+            let tile = tile.clone();
+            async move { actix_web::HttpResponse::Ok().body(tile) }
+        })
+        .await;
+        let tiles = TileServer::mock(&[&mock.url]).with_tile_size(256);
+        let mut img = image::RgbaImage::new(1200, 630);
+        let task = OverlayMapTask::new("room", 48.26842603718826, 11.677995005953209, None)
+            .with_tile_size(tiles.tile_size());
+        assert!(task.draw_onto(&tiles, &mut img).await);
+        let map_size = task.map_size(&img);
+        assert!(mock.requests() as u32 <= OverlayMapTask::max_tile_count(map_size, 256));
+        assert!(mock.requests() as u32 > OverlayMapTask::max_tile_count(map_size, 512));
+        for y in 0..map_size.1 {
+            for x in 0..map_size.0 {
+                assert_eq!(img.get_pixel(x, y).0, [0, 200, 0, 255], "pixel {x}/{y}");
+            }
+        }
+    }
+
     #[test]
     fn ranged_test() {
         assert_range_eq(0, 0, (0, 2), (0, 0));
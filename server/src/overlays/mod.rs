@@ -1,2 +1,3 @@
+pub mod assets;
 pub mod map;
 pub mod text;
@@ -1,29 +1,204 @@
-use ab_glyph::{FontArc, PxScale};
+use ab_glyph::{point, Font, FontArc, Glyph, GlyphId, PxScale, ScaleFont};
 use image::Rgba;
 use imageproc::definitions::HasBlack;
-use imageproc::drawing::{draw_text_mut, text_size};
+use rustybuzz::{Direction, Face, UnicodeBuffer};
+use std::borrow::Cow;
 use std::fmt;
 use std::fmt::Formatter;
+use std::ops::Range;
 use std::sync::OnceLock;
+use unicode_bidi::BidiInfo;
+use unicode_segmentation::UnicodeSegmentation;
 
-pub fn cantarell_bold() -> &'static FontArc {
-    static CANTARELL_BOLD: OnceLock<FontArc> = OnceLock::new();
-    CANTARELL_BOLD
-        .get_or_init(|| FontArc::try_from_slice(include_bytes!("font/Cantarell-Bold.ttf")).unwrap())
+use crate::overlays::assets;
+
+/// Fonts which are tried in order until one has a glyph for a character
+///
+/// Cantarell only covers latin scripts.
+/// DejaVu Sans is the fallback for greek, cyrillic, hebrew and arabic, the [`cjk_fallback`] for japanese and chinese.
+pub struct FontChain {
+    fonts: Vec<FontArc>,
+    /// the same fonts, for shaping the text
+    faces: Vec<Face<'static>>,
+    /// `font-weight` of the fonts, for viewers which render the text themselves
+    weight: &'static str,
 }
-pub fn cantarell_regular() -> &'static FontArc {
-    static CANTARELL_REGULAR: OnceLock<FontArc> = OnceLock::new();
+
+impl FontChain {
+    fn new(fonts: Vec<&'static [u8]>, weight: &'static str) -> Self {
+        assert!(!fonts.is_empty(), "a font chain needs a primary font");
+        let faces = fonts
+            .iter()
+            .map(|data| Face::from_slice(data, 0).expect("fonts of the chain are valid"))
+            .collect();
+        let fonts = fonts
+            .into_iter()
+            .map(|data| FontArc::try_from_slice(data).expect("fonts of the chain are valid"))
+            .collect();
+        Self {
+            fonts,
+            faces,
+            weight,
+        }
+    }
+
+    /// index of the first font which has a glyph for `c`.
+    /// If no font has one, the primary font is used to render its `.notdef` glyph
+    fn font_for(&self, c: char) -> usize {
+        self.fonts
+            .iter()
+            .position(|font| font.glyph_id(c).0 != 0)
+            .unwrap_or(0)
+    }
+
+    /// like [`Self::font_for`], but for all characters of a grapheme cluster, so that combining marks stay with their base character.
+    /// If no font has all of them, the font of the base character is used
+    fn font_for_grapheme(&self, grapheme: &str) -> usize {
+        self.fonts
+            .iter()
+            .position(|font| grapheme.chars().all(|c| font.glyph_id(c).0 != 0))
+            .unwrap_or_else(|| self.font_for(grapheme.chars().next().unwrap_or_default()))
+    }
+
+    /// positions the glyphs of `text` on a shared baseline, returning the glyphs and the total width
+    ///
+    /// Glyphs are returned from left to right, i.e. right-to-left runs are reversed.
+    /// Each run of one direction and font is shaped, so that e.g. arabic letters are joined and combining marks are placed on their base character.
+    fn layout(&self, scale: PxScale, text: &str) -> (Vec<(usize, Glyph)>, f32) {
+        let ascent = self.fonts[0].as_scaled(scale).ascent();
+        let mut glyphs = Vec::new();
+        let mut x = 0.0;
+        for (run, direction) in visual_runs(text) {
+            let mut segments = self.font_segments(&text[run]);
+            if direction == Direction::RightToLeft {
+                segments.reverse();
+            }
+            for (index, segment) in segments {
+                let font = self.fonts[index].as_scaled(scale);
+                let (h_factor, v_factor) = (font.h_scale_factor(), font.v_scale_factor());
+                let mut buffer = UnicodeBuffer::new();
+                buffer.push_str(segment);
+                buffer.set_direction(direction);
+                let shaped = rustybuzz::shape(&self.faces[index], &[], buffer);
+                for (info, position) in shaped.glyph_infos().iter().zip(shaped.glyph_positions()) {
+                    let id = GlyphId(info.glyph_id as u16);
+                    let glyph_x = x + position.x_offset as f32 * h_factor;
+                    let glyph_y = ascent - position.y_offset as f32 * v_factor;
+                    glyphs.push((
+                        index,
+                        id.with_scale_and_position(scale, point(glyph_x, glyph_y)),
+                    ));
+                    x += position.x_advance as f32 * h_factor;
+                }
+            }
+        }
+        (glyphs, x)
+    }
+
+    /// splits `text` into the longest segments which are drawn in one font, in logical order
+    fn font_segments<'a>(&self, text: &'a str) -> Vec<(usize, &'a str)> {
+        let mut segments: Vec<(usize, Range<usize>)> = Vec::new();
+        for (start, grapheme) in text.grapheme_indices(true) {
+            let index = self.font_for_grapheme(grapheme);
+            let end = start + grapheme.len();
+            match segments.last_mut() {
+                Some((previous, range)) if *previous == index => range.end = end,
+                _ => segments.push((index, start..end)),
+            }
+        }
+        segments
+            .into_iter()
+            .map(|(index, range)| (index, &text[range]))
+            .collect()
+    }
+}
+
+/// Splits `text` into runs of one direction, in the visual order in which they are drawn from left to right.
+///
+/// Runs of right-to-left scripts like hebrew or arabic are ordered according to the unicode bidi algorithm.
+/// The glyphs within a run are ordered by the shaper.
+fn visual_runs(text: &str) -> Vec<(Range<usize>, Direction)> {
+    let info = BidiInfo::new(text, None);
+    if !info.has_rtl() {
+        return vec![(0..text.len(), Direction::LeftToRight)];
+    }
+    info.paragraphs
+        .iter()
+        .flat_map(|paragraph| {
+            let (levels, runs) = info.visual_runs(paragraph, paragraph.range.clone());
+            runs.into_iter().map(move |run| {
+                let direction = if levels[run.start].is_rtl() {
+                    Direction::RightToLeft
+                } else {
+                    Direction::LeftToRight
+                };
+                (run, direction)
+            })
+        })
+        .collect()
+}
+
+/// Fallback for japanese and chinese, which only comes in one weight
+///
+/// Only a subset of Noto Sans JP is embedded: kana and the common kanji, including the common hanzi they share.
+/// Deployments needing more (e.g. korean hangul or simplified hanzi like `业`) can place a complete font like Noto Sans CJK into the assets directory.
+fn cjk_fallback() -> &'static [u8] {
+    static CJK_FALLBACK: OnceLock<&'static [u8]> = OnceLock::new();
+    CJK_FALLBACK.get_or_init(|| {
+        assets::load_font(
+            assets::asset_dir().as_deref(),
+            "NotoSansCJK-Regular.otf",
+            include_bytes!("font/NotoSansJP-Subset.otf"),
+        )
+    })
+}
+
+pub fn cantarell_bold() -> &'static FontChain {
+    static CANTARELL_BOLD: OnceLock<FontChain> = OnceLock::new();
+    CANTARELL_BOLD.get_or_init(|| {
+        let fonts: Vec<&'static [u8]> = vec![
+            assets::load_font(
+                assets::asset_dir().as_deref(),
+                "Cantarell-Bold.ttf",
+                include_bytes!("font/Cantarell-Bold.ttf"),
+            ),
+            include_bytes!("font/DejaVuSans-Bold.ttf"),
+            cjk_fallback(),
+        ];
+        FontChain::new(fonts, "bold")
+    })
+}
+pub fn cantarell_regular() -> &'static FontChain {
+    static CANTARELL_REGULAR: OnceLock<FontChain> = OnceLock::new();
     CANTARELL_REGULAR.get_or_init(|| {
-        FontArc::try_from_slice(include_bytes!("font/Cantarell-Regular.ttf")).unwrap()
+        let fonts: Vec<&'static [u8]> = vec![
+            assets::load_font(
+                assets::asset_dir().as_deref(),
+                "Cantarell-Regular.ttf",
+                include_bytes!("font/Cantarell-Regular.ttf"),
+            ),
+            include_bytes!("font/DejaVuSans.ttf"),
+            cjk_fallback(),
+        ];
+        FontChain::new(fonts, "normal")
     })
 }
 const SCALE: PxScale = PxScale { x: 35.0, y: 35.0 };
 
+/// the width in pixels which `text` has when rendered in `font` at `scale`
+pub fn text_width(font: &FontChain, scale: PxScale, text: &str) -> u32 {
+    font.layout(scale, text).1.ceil() as u32
+}
+
 pub struct OverlayText {
     x: i32,
     y: i32,
+    scale: PxScale,
+    color: Rgba<u8>,
+    /// contrasting color and width in pixels of an outline drawn around the glyphs
+    outline: Option<(Rgba<u8>, u32)>,
     text: String,
-    font: &'static FontArc,
+    font: &'static FontChain,
 }
 
 impl fmt::Debug for OverlayText {
@@ -31,16 +206,22 @@ impl fmt::Debug for OverlayText {
         f.debug_struct("OverlayText")
             .field("x", &self.x)
             .field("y", &self.y)
+            .field("scale", &self.scale.y)
+            .field("color", &self.color)
+            .field("outline", &self.outline)
             .field("text", &self.text)
             .finish()
     }
 }
 
 impl OverlayText {
-    pub fn with(text: &str, font: &'static FontArc) -> Self {
+    pub fn with(text: &str, font: &'static FontChain) -> Self {
         Self {
             x: 0,
             y: 0,
+            scale: SCALE,
+            color: Rgba::black(),
+            outline: None,
             text: text.to_string(),
             font,
         }
@@ -49,18 +230,518 @@ impl OverlayText {
     pub fn at(self, x: i32, y: i32) -> Self {
         Self { x, y, ..self }
     }
+    /// like [`Self::at`], but `baseline` is where the glyphs sit instead of the top of the text
+    pub fn with_baseline_at(self, x: i32, baseline: i32) -> Self {
+        let y = baseline + self.ascent().round() as i32;
+        self.at(x, y)
+    }
+    /// scales the text size relative to the default size of 35px
+    pub fn scaled(self, factor: f32) -> Self {
+        let scale = PxScale {
+            x: SCALE.x * factor,
+            y: SCALE.y * factor,
+        };
+        Self { scale, ..self }
+    }
+    pub fn colored(self, color: Rgba<u8>) -> Self {
+        Self { color, ..self }
+    }
+    /// surrounds the glyphs with a `width` pixels wide outline, to keep the text legible on busy backgrounds
+    pub fn outlined(self, color: Rgba<u8>, width: u32) -> Self {
+        let outline = (width > 0).then_some((color, width));
+        Self { outline, ..self }
+    }
+
+    /// the width in pixels which the text has when drawn
+    pub fn width(&self) -> u32 {
+        text_width(self.font, self.scale, &self.text)
+    }
+    /// how far in pixels the glyphs of the primary font reach above the baseline
+    pub fn ascent(&self) -> f32 {
+        self.font.fonts[0].as_scaled(self.scale).ascent()
+    }
+    /// how far in pixels the glyphs of the primary font reach below the baseline, as a negative number
+    pub fn descent(&self) -> f32 {
+        self.font.fonts[0].as_scaled(self.scale).descent()
+    }
+
+    /// Word-wraps the text into at most `max_lines` lines, which are each at most `max_width` pixels wide.
+    ///
+    /// If the text does not fit, the last line is ellipsized.
+    /// The lines keep the font, scale and color but still need to be positioned via [`Self::at`]
+    pub fn wrapped(self, max_width: u32, max_lines: usize) -> Vec<Self> {
+        let fits = |line: &str| text_width(self.font, self.scale, line) <= max_width;
+        let mut lines: Vec<String> = Vec::new();
+        let mut current = String::new();
+        for word in self.text.split_whitespace() {
+            let candidate = if current.is_empty() {
+                word.to_string()
+            } else {
+                format!("{current} {word}")
+            };
+            if current.is_empty() || fits(&candidate) {
+                current = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut current, word.to_string()));
+            }
+        }
+        if !current.is_empty() {
+            lines.push(current);
+        }
+        let max_lines = max_lines.max(1);
+        if lines.len() > max_lines {
+            let overflow = lines.split_off(max_lines).join(" ");
+            let last = lines.last_mut().unwrap();
+            *last = self.fit_to_width(&format!("{last} {overflow}"), max_width, true);
+        }
+        // single words can be wider than a line
+        for line in &mut lines {
+            if !fits(line) {
+                *line = self.fit_to_width(line, max_width, false);
+            }
+        }
+        lines
+            .into_iter()
+            .map(|text| Self { text, ..self })
+            .collect()
+    }
+
+    /// Truncates the text to be at most `max_width` pixels wide.
+    ///
+    /// An ellipsis is only appended if the text had to be shortened.
+    /// Text is cut between grapheme clusters, so combining characters stay with their base character.
+    pub fn truncated_to_width(self, max_width: u32) -> Self {
+        let text = self.fit_to_width(&self.text, max_width, false);
+        Self { text, ..self }
+    }
+
+    /// shortens `text` until it fits into `max_width` pixels.
+    /// `always_ellipsize` is used if the text was already shortened elsewhere
+    fn fit_to_width(&self, text: &str, max_width: u32, always_ellipsize: bool) -> String {
+        let fits = |text: &str| text_width(self.font, self.scale, text) <= max_width;
+        if !always_ellipsize && fits(text) {
+            return text.to_string();
+        }
+        let graphemes = text.graphemes(true).collect::<Vec<_>>();
+        for end in (0..=graphemes.len()).rev() {
+            let candidate = format!("{}…", graphemes[..end].concat().trim_end());
+            if fits(&candidate) {
+                return candidate;
+            }
+        }
+        "…".to_string()
+    }
+
+    /// The text as svg `<text>` element, positioned like [`Self::draw_onto`] would on an image of `width`x`height`
+    ///
+    /// The glyphs are left to the viewer, so the text stays sharp at every size.
+    /// Where a font has no glyph, the viewer falls back to its own fonts instead of the ones of the [`FontChain`].
+    pub fn to_svg(&self, (width, height): (u32, u32)) -> String {
+        let font = &self.font.fonts[0];
+        let ascent = self.ascent();
+        // css sizes fonts by their em square, ab_glyph by their ascent to descent
+        let em = font
+            .units_per_em()
+            .unwrap_or_else(|| font.height_unscaled());
+        let size = self.scale.y * em / font.height_unscaled();
+        let x = width as i32 - self.x;
+        let baseline = height as f32 - self.y as f32 + ascent;
+        let mut svg = format!(
+            r#"<text x="{x}" y="{baseline:.1}" text-anchor="end" font-family="Cantarell, DejaVu Sans, Noto Sans JP, sans-serif" font-weight="{weight}" font-size="{size:.1}" fill="{fill}""#,
+            weight = self.font.weight,
+            fill = svg_color(self.color),
+        );
+        if let Some((color, width)) = self.outline {
+            // the stroke is centered on the outline of the glyphs => half of it is covered by the fill
+            svg.push_str(&format!(
+                r#" stroke="{stroke}" stroke-width="{stroke_width}" paint-order="stroke""#,
+                stroke = svg_color(color),
+                stroke_width = 2 * width,
+            ));
+        }
+        format!("{svg}>{}</text>", escape_xml(&self.text))
+    }
 
     #[tracing::instrument(skip(img))]
     pub fn draw_onto(self, img: &mut image::RgbaImage) {
-        let (w, _) = text_size(SCALE, self.font, &self.text);
-        draw_text_mut(
-            img,
-            Rgba::black(),
-            img.width() as i32 - w as i32 - self.x,
-            img.height() as i32 - self.y,
-            SCALE,
-            self.font,
-            &self.text,
+        let (glyphs, width) = self.font.layout(self.scale, &self.text);
+        let origin_x = img.width() as i32 - width.ceil() as i32 - self.x;
+        let origin_y = img.height() as i32 - self.y;
+        if let Some((color, width)) = self.outline {
+            let width = width as i32;
+            for dx in -width..=width {
+                for dy in -width..=width {
+                    if (dx, dy) != (0, 0) && dx * dx + dy * dy <= width * width {
+                        self.draw_glyphs(img, &glyphs, (origin_x + dx, origin_y + dy), color);
+                    }
+                }
+            }
+        }
+        self.draw_glyphs(img, &glyphs, (origin_x, origin_y), self.color);
+    }
+
+    fn draw_glyphs(
+        &self,
+        img: &mut image::RgbaImage,
+        glyphs: &[(usize, Glyph)],
+        (origin_x, origin_y): (i32, i32),
+        color: Rgba<u8>,
+    ) {
+        for (index, glyph) in glyphs {
+            let Some(outline) = self.font.fonts[*index].outline_glyph(glyph.clone()) else {
+                // e.g. whitespace
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|x, y, coverage| {
+                let x = origin_x + bounds.min.x as i32 + x as i32;
+                let y = origin_y + bounds.min.y as i32 + y as i32;
+                if x < 0 || y < 0 || x >= img.width() as i32 || y >= img.height() as i32 {
+                    return;
+                }
+                let pixel = img.get_pixel_mut(x as u32, y as u32);
+                blend(pixel, color, coverage.clamp(0.0, 1.0));
+            });
+        }
+    }
+}
+
+/// `color` as svg paint, including its transparency
+fn svg_color(Rgba([r, g, b, a]): Rgba<u8>) -> String {
+    format!("rgba({r},{g},{b},{:.3})", f32::from(a) / 255.0)
+}
+
+/// escapes `text` for use in xml content or attributes
+fn escape_xml(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// mixes `color` into `pixel`, weighted by how much of the pixel the glyph covers
+fn blend(pixel: &mut Rgba<u8>, color: Rgba<u8>, coverage: f32) {
+    for (channel, target) in pixel.0.iter_mut().zip(color.0) {
+        *channel =
+            (f32::from(*channel) * (1.0 - coverage) + f32::from(target) * coverage).round() as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn lines(text: &str, max_width: u32) -> Vec<String> {
+        OverlayText::with(text, cantarell_bold())
+            .wrapped(max_width, 2)
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn short_text_stays_on_one_line() {
+        assert_eq!(lines("5121.EG.003", 700), vec!["5121.EG.003"]);
+        assert_eq!(lines("", 700), Vec::<String>::new());
+    }
+
+    #[test]
+    fn long_text_wraps() {
+        let name = "Zentrum Mathematik, Boltzmannstraße 3, Hörsaal 2";
+        let wrapped = lines(name, 700);
+        assert_eq!(wrapped.len(), 2);
+        assert_eq!(wrapped.join(" "), name);
+        for line in wrapped {
+            assert!(text_width(cantarell_bold(), SCALE, &line) <= 700, "{line}");
+        }
+    }
+
+    #[test]
+    fn truncation_by_pixel_width() {
+        let truncated = |text: &str, max_width| {
+            OverlayText::with(text, cantarell_bold())
+                .truncated_to_width(max_width)
+                .text
+        };
+        // fitting text is left alone
+        assert_eq!(truncated("MI HS 1", 700), "MI HS 1");
+        // wide glyphs need more space than narrow ones
+        let wide = truncated(&"W".repeat(40), 300);
+        let narrow = truncated(&"i".repeat(40), 300);
+        assert!(wide.ends_with('…') && narrow.ends_with('…'));
+        assert!(wide.chars().count() < narrow.chars().count());
+        assert_eq!(
+            narrow,
+            format!("{}…", "i".repeat(narrow.chars().count() - 1))
+        );
+        for text in [wide, narrow] {
+            let width = text_width(cantarell_bold(), SCALE, &text);
+            assert!(width <= 300, "{text} is {width}px wide");
+            assert!(width > 240, "{text} is only {width}px wide");
+        }
+    }
+
+    #[test]
+    fn truncation_keeps_graphemes_intact() {
+        // e + combining acute accent
+        let text = OverlayText::with(&"e\u{301}".repeat(40), cantarell_bold())
+            .truncated_to_width(200)
+            .text;
+        let shortened = text.strip_suffix('…').unwrap();
+        assert!(!shortened.is_empty());
+        assert!(
+            shortened.graphemes(true).all(|g| g == "e\u{301}"),
+            "{text:?}"
+        );
+    }
+
+    #[test]
+    fn truncation_keeps_emoji_sequences_intact() {
+        // family emoji, joined by zero width joiners
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let name = format!("Raum {family}").repeat(20);
+        let graphemes = name.graphemes(true).collect::<Vec<_>>();
+        let wrapped = lines(&name, 300);
+        let truncated = OverlayText::with(&name, cantarell_bold())
+            .truncated_to_width(300)
+            .text;
+        for text in [wrapped.last().unwrap(), &truncated] {
+            let shortened = text.strip_suffix('…').unwrap();
+            let start = name.find(shortened).unwrap();
+            // the cut happened on a cluster boundary
+            let clusters = shortened.graphemes(true).collect::<Vec<_>>();
+            let start_cluster = name[..start].graphemes(true).count();
+            assert_eq!(
+                clusters,
+                graphemes[start_cluster..start_cluster + clusters.len()],
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn svg_text_is_positioned_like_drawn_text() {
+        let svg = OverlayText::with("Hörsaal <1> & \"2\"", cantarell_bold())
+            .at(10, 45)
+            .colored(Rgba([255, 255, 255, 255]))
+            .outlined(Rgba([0, 0, 0, 255]), 1)
+            .to_svg((1200, 630));
+        assert!(svg.starts_with(r#"<text x="1190" "#), "{svg}");
+        assert!(
+            svg.ends_with(">Hörsaal &lt;1&gt; &amp; &quot;2&quot;</text>"),
+            "{svg}"
         );
+        assert!(svg.contains(r#"text-anchor="end""#), "{svg}");
+        assert!(svg.contains(r#"font-weight="bold""#), "{svg}");
+        assert!(svg.contains(r#"fill="rgba(255,255,255,1.000)""#), "{svg}");
+        assert!(
+            svg.contains(r#"stroke="rgba(0,0,0,1.000)" stroke-width="2""#),
+            "{svg}"
+        );
+        // the baseline is below the top of the text
+        let baseline = svg
+            .split(r#"y=""#)
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .and_then(|y| y.parse::<f32>().ok())
+            .unwrap();
+        assert!((630.0 - 45.0..630.0).contains(&baseline), "{baseline}");
+
+        let regular = OverlayText::with("MI", cantarell_regular()).to_svg((100, 100));
+        assert!(regular.contains(r#"font-weight="normal""#), "{regular}");
+        assert!(!regular.contains("stroke"), "{regular}");
+    }
+
+    #[test]
+    fn missing_glyphs_fall_back() {
+        let chain = cantarell_bold();
+        assert_eq!(chain.font_for('a'), 0);
+        assert_eq!(chain.font_for('ß'), 0);
+        assert_eq!(chain.font_for('Ω'), 1);
+        assert_eq!(chain.font_for('ש'), 1);
+        assert_eq!(chain.font_for('東'), 2);
+        assert_eq!(chain.font_for('ミ'), 2);
+        // rare characters are not in the embedded subset
+        assert_eq!(chain.font_for('龘'), 0);
+    }
+
+    fn render(text: &str, font: &'static FontChain) -> image::RgbaImage {
+        let mut img = image::RgbaImage::from_pixel(200, 50, Rgba([255, 255, 255, 255]));
+        OverlayText::with(text, font).at(10, 45).draw_onto(&mut img);
+        img
+    }
+
+    #[test]
+    fn fallback_glyphs_are_no_tofu() {
+        static CANTARELL_ONLY: OnceLock<FontChain> = OnceLock::new();
+        let cantarell_only = CANTARELL_ONLY.get_or_init(|| {
+            FontChain::new(vec![include_bytes!("font/Cantarell-Bold.ttf")], "bold")
+        });
+        let drawn = |img: &image::RgbaImage| img.pixels().filter(|p| p.0[0] < 128).count();
+
+        let with_fallback = render("Ω", cantarell_bold());
+        let tofu = render("Ω", cantarell_only);
+        assert!(drawn(&with_fallback) > 0);
+        assert!(drawn(&tofu) > 0, "cantarell renders its .notdef box");
+        assert_ne!(with_fallback, tofu);
+        // mixing scripts renders both
+        let mixed = render("Hörsaal Ω", cantarell_bold());
+        let latin = render("Hörsaal", cantarell_bold());
+        assert!(drawn(&mixed) > drawn(&latin));
+    }
+
+    #[test]
+    fn cjk_names_are_no_tofu() {
+        static CANTARELL_ONLY: OnceLock<FontChain> = OnceLock::new();
+        let cantarell_only = CANTARELL_ONLY.get_or_init(|| {
+            FontChain::new(vec![include_bytes!("font/Cantarell-Regular.ttf")], "normal")
+        });
+        // japanese for "Technical University of Munich" and simplified chinese for "Garching campus"
+        for name in ["ミュンヘン工科大学", "加尔兴校区"] {
+            let glyphs = |chain: &FontChain| {
+                name.chars()
+                    .map(|c| chain.fonts[chain.font_for(c)].glyph_id(c))
+                    .collect::<Vec<_>>()
+            };
+            assert!(
+                glyphs(cantarell_regular()).iter().all(|id| id.0 != 0),
+                "{name} has a glyph for every character"
+            );
+            // every .notdef box looks the same, while real glyphs differ from each other
+            let with_fallback = render(name, cantarell_regular());
+            let tofu = render(name, cantarell_only);
+            assert_ne!(with_fallback, tofu, "{name}");
+            let first = render(
+                &name[..name.chars().next().unwrap().len_utf8()],
+                cantarell_regular(),
+            );
+            // the fallback glyphs advance the pen, instead of being drawn on top of each other
+            let width = |(min_x, _, max_x, _): (u32, u32, u32, u32)| max_x - min_x;
+            assert!(width(darkened_bounds(&first)) * 5 < width(darkened_bounds(&with_fallback)));
+        }
+    }
+
+    #[test]
+    fn right_to_left_runs_are_ordered_visually() {
+        let runs = |text: &'static str| {
+            visual_runs(text)
+                .into_iter()
+                .map(|(range, direction)| (&text[range], direction))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(runs("MI HS 1"), vec![("MI HS 1", Direction::LeftToRight)]);
+        // hebrew "shalom"
+        assert_eq!(runs("שלום"), vec![("שלום", Direction::RightToLeft)]);
+        // a number following right-to-left text belongs to it, i.e. this is drawn as "Raum 1 םולש"
+        assert_eq!(
+            runs("Raum שלום 1"),
+            vec![
+                ("Raum ", Direction::LeftToRight),
+                ("1", Direction::LeftToRight),
+                ("שלום ", Direction::RightToLeft)
+            ]
+        );
+    }
+
+    #[test]
+    fn first_drawn_arabic_glyph_is_the_logically_last() {
+        // arabic "building"
+        let text = "مبنى";
+        let chain = cantarell_bold();
+        let (glyphs, _) = chain.layout(SCALE, text);
+        let glyph_id = |c| chain.fonts[chain.font_for(c)].glyph_id(c);
+        // the letters are joined => the last one is drawn in its final form and the first one in its initial form
+        let final_alef_maksura = glyph_id('\u{FEF0}');
+        let initial_meem = glyph_id('\u{FEE3}');
+        assert_ne!(initial_meem, glyph_id('م'));
+        assert_eq!(glyphs.first().unwrap().1.id, final_alef_maksura);
+        assert_eq!(glyphs.last().unwrap().1.id, initial_meem);
+        // glyphs are still laid out from left to right
+        assert!(glyphs
+            .windows(2)
+            .all(|pair| pair[0].1.position.x < pair[1].1.position.x));
+    }
+
+    #[test]
+    fn combining_marks_stay_with_their_base() {
+        let chain = cantarell_bold();
+        // cantarell has no combining acute => the whole "é" comes from the fallback, which composes it
+        let (glyphs, _) = chain.layout(SCALE, "Cafe\u{301}");
+        let fonts = glyphs.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        assert_eq!(fonts, vec![0, 0, 0, 1]);
+
+        // hebrew "shalom" with vowel points, which are drawn onto their letters instead of taking room of their own
+        let (plain, plain_width) = chain.layout(SCALE, "שלום");
+        let (pointed, pointed_width) = chain.layout(SCALE, "שָׁלוֹם");
+        assert_eq!(pointed.len(), plain.len() + 3);
+        assert_eq!(pointed_width, plain_width);
+        assert!(pointed.iter().all(|(_, glyph)| plain
+            .iter()
+            .any(|(_, letter)| letter.position.x == glyph.position.x)));
+    }
+
+    /// bounding box `(min_x, min_y, max_x, max_y)` of the pixels which are darker than the white background
+    fn darkened_bounds(img: &image::RgbaImage) -> (u32, u32, u32, u32) {
+        img.enumerate_pixels()
+            .filter(|(_, _, p)| p.0[0] < 200)
+            .fold((u32::MAX, u32::MAX, 0, 0), |(x0, y0, x1, y1), (x, y, _)| {
+                (x0.min(x), y0.min(y), x1.max(x), y1.max(y))
+            })
+    }
+
+    #[test]
+    fn outline_grows_the_text() {
+        let draw = |text: OverlayText| {
+            let mut img = image::RgbaImage::from_pixel(200, 60, Rgba([255, 255, 255, 255]));
+            text.at(20, 50).draw_onto(&mut img);
+            img
+        };
+        let plain = darkened_bounds(&draw(OverlayText::with("MI", cantarell_bold())));
+        let outlined = darkened_bounds(&draw(
+            OverlayText::with("MI", cantarell_bold()).outlined(Rgba([60, 60, 60, 255]), 2),
+        ));
+        assert!(
+            outlined.0 < plain.0 && outlined.1 < plain.1,
+            "{outlined:?} vs {plain:?}"
+        );
+        assert!(
+            outlined.2 > plain.2 && outlined.3 > plain.3,
+            "{outlined:?} vs {plain:?}"
+        );
+        // a zero width outline is no outline
+        let zero = darkened_bounds(&draw(
+            OverlayText::with("MI", cantarell_bold()).outlined(Rgba([60, 60, 60, 255]), 0),
+        ));
+        assert_eq!(zero, plain);
+    }
+
+    #[test]
+    fn overflowing_text_is_ellipsized() {
+        let name = "Zentrum Mathematik, Boltzmannstraße 3, Hörsaal 2 (Anton-Mayer-Hörsaal) mit sehr langem Zusatz";
+        let wrapped = lines(name, 500);
+        assert_eq!(wrapped.len(), 2);
+        assert!(wrapped[1].ends_with('…'), "{wrapped:?}");
+        assert!(!wrapped[0].ends_with('…'), "{wrapped:?}");
+        for line in wrapped {
+            assert!(text_width(cantarell_bold(), SCALE, &line) <= 500, "{line}");
+        }
+        // single words which are too long for a line are shortened as well
+        let wrapped = lines("Donaudampfschifffahrtsgesellschaftskapitän", 200);
+        assert_eq!(wrapped.len(), 1);
+        assert!(wrapped[0].ends_with('…'));
+        assert!(text_width(cantarell_bold(), SCALE, &wrapped[0]) <= 200);
     }
 }
@@ -0,0 +1,202 @@
+//! One concise line per preview request, for reconstructing what happened during an incident
+//!
+//! The metrics only tell how many requests there were, the access log tells which ones.
+//! Under high load, only a sample of the requests is logged, as the log would otherwise drown everything else.
+
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::HttpResponse;
+use tracing::info;
+
+/// What the preview cache contributed to a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CacheOutcome {
+    Hit,
+    Miss,
+    /// The client asked for the cache to be skipped via `nocache`
+    Bypass,
+}
+
+impl CacheOutcome {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+            Self::Bypass => "bypass",
+        }
+    }
+}
+
+/// Remembers `outcome` with `response`, so that the [`AccessLog`] can report it
+pub(super) fn with_cache_outcome(
+    mut response: HttpResponse,
+    outcome: Option<CacheOutcome>,
+) -> HttpResponse {
+    if let Some(outcome) = outcome {
+        response.extensions_mut().insert(outcome);
+    }
+    response
+}
+
+/// Logs every request until `per_second` requests were logged within a second, and a `sample_rate` share of the rest
+#[derive(Debug, Clone)]
+pub(super) struct AccessLog {
+    per_second: u32,
+    sample_rate: f64,
+    /// Start of the current second and how many requests it had
+    window: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl AccessLog {
+    pub(super) fn new(per_second: u32, sample_rate: f64) -> Self {
+        Self {
+            per_second,
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            window: Arc::new(Mutex::new((Instant::now(), 0))),
+        }
+    }
+
+    /// Whether the current request is logged
+    fn is_sampled(&self) -> bool {
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        if window.0.elapsed() >= Duration::from_secs(1) {
+            *window = (Instant::now(), 0);
+        }
+        window.1 = window.1.saturating_add(1);
+        window.1 <= self.per_second || rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Logs how `response` answered the request of `client` for the preview of `id`, if it is sampled
+    ///
+    /// `client` should come from [`super::PreviewConfig::client_addr`], so that spoofed headers are only believed behind trusted proxies
+    pub(super) fn record(
+        &self,
+        client: Option<&str>,
+        id: &str,
+        response: &HttpResponse,
+        started: Instant,
+    ) {
+        if !self.is_sampled() {
+            return;
+        }
+        let bytes = match response.body().size() {
+            BodySize::Sized(bytes) => Some(bytes),
+            BodySize::None | BodySize::Stream => None,
+        };
+        let cache = response
+            .extensions()
+            .get::<CacheOutcome>()
+            .map_or("none", |outcome| outcome.name());
+        let region = client.map_or_else(|| "unknown".to_string(), client_region);
+        info!(
+            id = %id,
+            status = response.status().as_u16(),
+            bytes,
+            duration_ms = started.elapsed().as_millis() as u64,
+            cache = %cache,
+            region = %region,
+            "preview access"
+        );
+    }
+}
+
+/// The network a client is in (`/24` for IPv4, `/48` for IPv6)
+///
+/// Narrows down where the traffic comes from without logging who exactly sent it
+fn client_region(client: &str) -> String {
+    // `X-Forwarded-For` might carry a port
+    let addr = client
+        .parse::<IpAddr>()
+        .or_else(|_| client.parse::<std::net::SocketAddr>().map(|addr| addr.ip()));
+    match addr {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{a:x}:{b:x}:{c:x}::/48")
+        }
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn regions_hide_the_client() {
+        assert_eq!(client_region("131.159.0.42"), "131.159.0.0/24");
+        assert_eq!(client_region("131.159.0.42:4711"), "131.159.0.0/24");
+        assert_eq!(
+            client_region("2001:4ca0:0:103::81bb:fe91"),
+            "2001:4ca0:0::/48"
+        );
+        assert_eq!(client_region("not-an-ip"), "unknown");
+    }
+
+    #[test]
+    fn requests_beyond_the_budget_are_sampled() {
+        let log = AccessLog::new(2, 0.0);
+        assert_eq!(
+            (0..5).map(|_| log.is_sampled()).collect::<Vec<_>>(),
+            vec![true, true, false, false, false]
+        );
+        // pretend the second is over
+        log.window.lock().unwrap().0 = Instant::now() - Duration::from_secs(1);
+        assert!(log.is_sampled());
+
+        let everything = AccessLog::new(0, 1.0);
+        assert!((0..100).all(|_| everything.is_sampled()));
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use super::super::harness::{PreviewHarness, TILE_COLOR};
+    use super::*;
+
+    #[actix_web::test]
+    #[tracing_test::traced_test]
+    async fn sampled_requests_are_logged_once() {
+        let harness = PreviewHarness::with_config(TILE_COLOR, |config| {
+            // nothing is within the budget => the request is only logged because it is sampled
+            config.access_log = AccessLog::new(0, 1.0);
+        })
+        .await;
+        let resp = harness.get("/api/locations/5121.EG.003/preview").await;
+        assert_eq!(resp.status, 200);
+        let bytes = resp.body.len();
+        logs_assert(|lines: &[&str]| {
+            let logged = lines
+                .iter()
+                .filter(|line| line.contains("preview access"))
+                .collect::<Vec<_>>();
+            let [line] = logged.as_slice() else {
+                return Err(format!(
+                    "expected exactly one access log line, got {logged:?}"
+                ));
+            };
+            for expected in [
+                "id=5121.EG.003".to_string(),
+                "status=200".to_string(),
+                format!("bytes={bytes}"),
+                "duration_ms=".to_string(),
+                // the harness disables the preview cache
+                "cache=none".to_string(),
+                "region=".to_string(),
+            ] {
+                if !line.contains(&expected) {
+                    return Err(format!("{expected} is missing in {line}"));
+                }
+            }
+            Ok(())
+        });
+    }
+}
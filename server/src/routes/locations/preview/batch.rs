@@ -0,0 +1,319 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::error::{PreviewError, PreviewErrorBody};
+use super::{render_and_cache, resolve_alias, sanitize_id, PreviewFormat, PreviewKey, QueryArgs};
+use crate::db::location::Location;
+use crate::localisation;
+
+/// How many previews can be prefetched with one request
+const MAX_BATCH_SIZE: usize = 50;
+/// How many previews of one batch are rendered at the same time
+const MAX_CONCURRENT_RENDERS: usize = 4;
+
+#[derive(Deserialize, Debug, utoipa::ToSchema)]
+struct BatchItem {
+    /// ID of a location
+    #[schema(example = "5121.EG.003")]
+    id: String,
+    #[serde(flatten, default)]
+    lang: localisation::LangQueryArgs,
+    #[serde(default)]
+    format: PreviewFormat,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+    /// The preview was rendered and is now cached
+    Rendered,
+    /// The preview was already cached
+    Cached,
+    /// The location does not exist
+    NotFound,
+    /// The id can not be the key of a location, e.g. because it is too long
+    Invalid,
+    /// Rendering was rate limited like for the preview endpoint. The preview can be prefetched again later
+    RateLimited,
+    /// The preview could not be rendered, e.g. because the tileserver is unavailable
+    Failed,
+}
+
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+struct BatchItemStatus {
+    /// ID of the location, as requested
+    id: String,
+    status: BatchStatus,
+}
+
+/// Prefetch previews
+///
+/// Renders the previews of up to 50 locations into the cache, so that requesting them later is fast.
+/// Only the status of each preview is returned, not the image itself.
+///
+/// Previews are rendered like the preview endpoint does without further arguments, except for `lang` and `format`.
+/// They are thus `png` with the light theme.
+///
+/// Every preview which has to be rendered counts against the rate limit of the preview endpoint, like requesting it would.
+#[utoipa::path(
+    tags=["locations"],
+    request_body = Vec<BatchItem>,
+    responses(
+        (status = 200, description = "**Status of every preview**, in the order in which they were requested", body = Vec<BatchItemStatus>, content_type = "application/json"),
+        (status = 400, description = "**Bad Request.** Too many previews were requested at once", body = PreviewErrorBody, content_type = "application/json", example = json!({"error": "at most 50 previews can be prefetched at once", "code": "bad_request"})),
+    )
+)]
+#[post("/api/locations/preview/batch")]
+pub async fn batch_handler(
+    req: HttpRequest,
+    items: web::Json<Vec<BatchItem>>,
+    data: web::Data<crate::AppData>,
+) -> Result<HttpResponse, PreviewError> {
+    if items.len() > MAX_BATCH_SIZE {
+        return Err(PreviewError::InvalidArgs(format!(
+            "at most {MAX_BATCH_SIZE} previews can be prefetched at once"
+        )));
+    }
+    let client = data.preview.client_addr(&req);
+    let statuses = futures::stream::iter(items.into_inner())
+        .map(|item| prime(&data, item, Some(client.as_deref().unwrap_or_default())))
+        .buffered(MAX_CONCURRENT_RENDERS)
+        .collect::<Vec<_>>()
+        .await;
+    Ok(HttpResponse::Ok().json(statuses))
+}
+
+/// Renders the preview of `item` into the cache, unless it is cached already
+///
+/// Rendering is charged to the rate limit of `client`, if there is one
+#[tracing::instrument(skip(data))]
+async fn prime(data: &crate::AppData, item: BatchItem, client: Option<&str>) -> BatchItemStatus {
+    let Ok(id) = sanitize_id(&item.id) else {
+        return item.with_status(BatchStatus::Invalid);
+    };
+    // the preview endpoint redirects aliases => only the preview of the key is ever requested
+    let id = match resolve_alias(&data.pool, &id).await {
+        Ok(key) => key.unwrap_or(id),
+        Err(e) => {
+            error!(error = ?e, id, "error requesting alias");
+            return item.with_status(BatchStatus::Failed);
+        }
+    };
+    let should_use_english = item.lang.should_use_english();
+    let location =
+        match Location::fetch_optional_in_any_language(&data.pool, &id, should_use_english).await {
+            Ok(Some(location)) => location,
+            Ok(None) => return item.with_status(BatchStatus::NotFound),
+            Err(e) => {
+                error!(error = ?e, id, "could not get data for location");
+                return item.with_status(BatchStatus::Failed);
+            }
+        };
+    let args = QueryArgs {
+        format: Some(item.format),
+        ..QueryArgs::default()
+    };
+    // warm what clients without an `Accept` header or with a wildcard one get
+    let Ok(key) = PreviewKey::new(id, should_use_english, &args, None, &location) else {
+        return item.with_status(BatchStatus::Invalid);
+    };
+    let cache = data.preview.cache.as_ref();
+    if let Some(cache) = cache {
+        if cache
+            .get(key.hashed(), location.last_calendar_scrape_at)
+            .await
+            .is_some()
+        {
+            return item.with_status(BatchStatus::Cached);
+        }
+    }
+    // like for the preview endpoint, only rendering is limited
+    if let (Some(limiter), Some(client)) = (&data.preview.rate_limit, client) {
+        if limiter.check(client).is_err() {
+            return item.with_status(BatchStatus::RateLimited);
+        }
+    }
+    match render_and_cache(&data.pool, &data.preview, location, &key).await {
+        Ok(_) => item.with_status(BatchStatus::Rendered),
+        Err(_) => item.with_status(BatchStatus::Failed),
+    }
+}
+
+/// How many previews of a [`prime_cache`] run had which [`BatchStatus`]
+///
+/// Priming is not rate limited. Ids which can not exist (e.g. typos in `PREVIEW_PRIME_IDS`) count as failed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PrimingReport {
+    pub rendered: usize,
+    pub cached: usize,
+    pub not_found: usize,
+    pub failed: usize,
+}
+
+/// Renders the previews of `ids` into the cache, so that the first requests after a deploy are fast
+///
+/// The previews are rendered like [`batch_handler`] does, just without the limit on the number of previews
+#[tracing::instrument(skip_all, fields(ids = ids.len()))]
+pub async fn prime_cache(data: &crate::AppData, ids: Vec<String>) -> PrimingReport {
+    let items = ids.into_iter().map(|id| BatchItem {
+        id,
+        lang: localisation::LangQueryArgs::default(),
+        format: PreviewFormat::default(),
+    });
+    let report = futures::stream::iter(items)
+        .map(|item| prime(data, item, None))
+        .buffer_unordered(MAX_CONCURRENT_RENDERS)
+        .fold(PrimingReport::default(), |mut report, item| async move {
+            match item.status {
+                BatchStatus::Rendered => report.rendered += 1,
+                BatchStatus::Cached => report.cached += 1,
+                BatchStatus::NotFound => report.not_found += 1,
+                BatchStatus::Failed | BatchStatus::Invalid | BatchStatus::RateLimited => {
+                    report.failed += 1;
+                }
+            }
+            report
+        })
+        .await;
+    info!(
+        rendered = report.rendered,
+        cached = report.cached,
+        not_found = report.not_found,
+        failed = report.failed,
+        "primed the preview cache"
+    );
+    report
+}
+
+impl BatchItem {
+    fn with_status(self, status: BatchStatus) -> BatchItemStatus {
+        BatchItemStatus {
+            id: self.id,
+            status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use actix_web::http::header::ACCEPT;
+    use actix_web::test;
+    use pretty_assertions::assert_eq;
+
+    use super::super::cache::PreviewCache;
+    use super::super::harness::{PreviewHarness, TILE_COLOR};
+    use super::super::rate_limit::RateLimiter;
+    use super::*;
+
+    fn post_batch(previews: &serde_json::Value) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/locations/preview/batch")
+            .set_json(previews)
+    }
+
+    #[actix_web::test]
+    async fn batch_primes_the_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let harness = PreviewHarness::with_config(TILE_COLOR, |config| {
+            config.cache = PreviewCache::new(cache_dir.path().to_path_buf(), 1024 * 1024);
+        })
+        .await;
+        let previews = serde_json::json!([
+            {"id": "5121.EG.003"},
+            {"id": "does-not-exist"},
+            {"id": "5121.EG.003", "lang": "en", "format": "square"},
+            {"id": "5121.EG.003<script>"},
+        ]);
+        let rendered = serde_json::json!([
+            {"id": "5121.EG.003", "status": "rendered"},
+            {"id": "does-not-exist", "status": "not_found"},
+            {"id": "5121.EG.003", "status": "rendered"},
+            {"id": "5121.EG.003<script>", "status": "invalid"},
+        ]);
+        let cached = serde_json::json!([
+            {"id": "5121.EG.003", "status": "cached"},
+            {"id": "does-not-exist", "status": "not_found"},
+            {"id": "5121.EG.003", "status": "cached"},
+            {"id": "5121.EG.003<script>", "status": "invalid"},
+        ]);
+        // the second request finds everything in the cache
+        for expected in [rendered, cached] {
+            let resp = harness.call(post_batch(&previews)).await;
+            assert_eq!(resp.status, 200);
+            assert_eq!(resp.json(), expected);
+        }
+        let requests = harness.tileserver.requests();
+        assert!(requests > 0);
+        // crawlers only sending wildcards get what was warmed
+        let req = test::TestRequest::get()
+            .uri("/api/locations/5121.EG.003/preview")
+            .insert_header((ACCEPT, "*/*"));
+        assert_eq!(harness.call(req).await.status, 200);
+        assert_eq!(
+            harness.tileserver.requests(),
+            requests,
+            "served from the cache"
+        );
+
+        let too_large = serde_json::json!(vec![serde_json::json!({"id": "5121.EG.003"}); 51]);
+        assert_eq!(harness.call(post_batch(&too_large)).await.status, 400);
+    }
+
+    #[actix_web::test]
+    async fn batches_count_against_the_rate_limit() {
+        // the harness has no preview cache => every preview has to be rendered
+        let harness = PreviewHarness::with_config(TILE_COLOR, |config| {
+            config.rate_limit = RateLimiter::new(0.01, 2);
+        })
+        .await;
+        let previews = serde_json::json!([
+            {"id": "5121.EG.003"},
+            {"id": "5121.EG.003", "format": "square"},
+            {"id": "5121.EG.003", "lang": "en"},
+        ]);
+        let resp = harness.call(post_batch(&previews)).await;
+        assert_eq!(resp.status, 200);
+        let statuses = resp.json();
+        let statuses = statuses.as_array().unwrap();
+        let count = |status: &str| statuses.iter().filter(|s| s["status"] == status).count();
+        // the renders happen concurrently => which one is limited is not known
+        assert_eq!(count("rendered"), 2, "{statuses:?}");
+        assert_eq!(count("rate_limited"), 1, "{statuses:?}");
+    }
+
+    #[actix_web::test]
+    async fn priming_fills_the_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let harness = PreviewHarness::with_config(TILE_COLOR, |config| {
+            config.cache = PreviewCache::new(cache_dir.path().to_path_buf(), 1024 * 1024);
+        })
+        .await;
+        let cached_files = || std::fs::read_dir(cache_dir.path()).unwrap().count();
+        assert_eq!(cached_files(), 0);
+
+        let ids = vec!["5121.EG.003".to_string(), "does-not-exist".to_string()];
+        let report = prime_cache(harness.data(), ids.clone()).await;
+        assert_eq!(
+            report,
+            PrimingReport {
+                rendered: 1,
+                not_found: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(cached_files(), 1);
+
+        let report = prime_cache(harness.data(), ids).await;
+        assert_eq!(
+            report,
+            PrimingReport {
+                cached: 1,
+                not_found: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(cached_files(), 1);
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs::FileTimes;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use tracing::{debug, error, info, warn};
+
+use crate::limited::vec::LimitedVec;
+
+const PREVIEW_CACHE_EVICTION_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// What the [`PreviewCache`] stores on disk
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PreviewCacheUsage {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// On-disk cache for fully rendered previews, bounded to `max_size` bytes
+///
+/// Rendering a preview is expensive (tile fetching + compositing + encoding), while the result rarely changes.
+/// Once the size is exceeded, the least recently used previews are evicted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreviewCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl PreviewCache {
+    /// Creates the cache in `dir`.
+    ///
+    /// Returns [`None`] if the directory can not be created, as caching is not essential for serving previews
+    pub fn new(dir: PathBuf, max_size: u64) -> Option<Self> {
+        match std::fs::create_dir_all(&dir) {
+            Ok(()) => Some(Self { dir, max_size }),
+            Err(e) => {
+                warn!(error = ?e, ?dir, "could not create the preview cache, disabling it");
+                None
+            }
+        }
+    }
+
+    /// How many previews are cached and how much space they need
+    ///
+    /// Walks the whole cache => this is expensive for large caches
+    pub fn usage(&self) -> std::io::Result<PreviewCacheUsage> {
+        let mut usage = PreviewCacheUsage::default();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            // previews which are being written are not cached yet
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                usage.entries += 1;
+                usage.bytes += metadata.len();
+            }
+        }
+        Ok(usage)
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}"))
+    }
+
+    /// Gets a cached preview
+    ///
+    /// Entries which are older than `not_before` are considered stale
+    pub async fn get(&self, key: u64, not_before: Option<DateTime<Utc>>) -> Option<LimitedVec<u8>> {
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || cache.get_blocking(key, not_before))
+            .await
+            .expect("reading the preview cache should not panic")
+    }
+
+    fn get_blocking(&self, key: u64, not_before: Option<DateTime<Utc>>) -> Option<LimitedVec<u8>> {
+        let path = self.path(key);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        if let Some(not_before) = not_before {
+            if modified < SystemTime::from(not_before) {
+                debug!(?path, "cached preview is stale");
+                return None;
+            }
+        }
+        match std::fs::read(&path) {
+            Ok(data) => {
+                Self::touch(&path);
+                Some(LimitedVec(data))
+            }
+            Err(e) => {
+                warn!(error = ?e, ?path, "could not read cached preview");
+                None
+            }
+        }
+    }
+
+    /// The modification time tells how fresh a preview is => the access time tracks when it was last used.
+    ///
+    /// Setting it explicitly works regardless of `noatime`/`relatime`
+    fn touch(path: &Path) {
+        let touched = std::fs::File::open(path)
+            .and_then(|f| f.set_times(FileTimes::new().set_accessed(SystemTime::now())));
+        if let Err(e) = touched {
+            debug!(error = ?e, ?path, "could not mark preview as recently used");
+        }
+    }
+
+    /// Stores a preview in the cache
+    ///
+    /// The preview is first written to a temporary file and then atomically renamed.
+    /// Concurrent requests for the same preview thus never observe half written files.
+    pub async fn insert(&self, key: u64, data: Vec<u8>) {
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || cache.insert_blocking(key, &data))
+            .await
+            .expect("writing to the preview cache should not panic");
+    }
+
+    fn insert_blocking(&self, key: u64, data: &[u8]) {
+        let path = self.path(key);
+        let res = tempfile::NamedTempFile::new_in(&self.dir).and_then(|mut file| {
+            file.write_all(data)?;
+            file.persist(&path).map_err(|e| e.error)?;
+            Ok(())
+        });
+        if let Err(e) = res {
+            warn!(error = ?e, ?path, "could not store preview in the cache");
+        }
+    }
+
+    pub async fn evict_periodically(self) {
+        let mut interval = tokio::time::interval(PREVIEW_CACHE_EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cache = self.clone();
+            match tokio::task::spawn_blocking(move || cache.evict_least_recently_used()).await {
+                Ok(Ok(evicted)) if evicted > 0 => {
+                    info!(
+                        evicted,
+                        max_size = self.max_size,
+                        "evicted previews from the cache"
+                    )
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => error!(error = ?e, "could not evict previews from the cache"),
+                Err(e) => error!(error = ?e, "preview cache eviction panicked"),
+            }
+        }
+    }
+
+    /// Removes the least recently used previews until the cache is smaller than `max_size`.
+    ///
+    /// Returns how many previews were evicted
+    fn evict_least_recently_used(&self) -> std::io::Result<usize> {
+        let mut previews = Vec::new();
+        let mut total_size = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            // previews which are being written are not cached yet
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            total_size += metadata.len();
+            let last_used = metadata.accessed().or_else(|_| metadata.modified())?;
+            previews.push((last_used, metadata.len(), entry.path()));
+        }
+        if total_size <= self.max_size {
+            return Ok(0);
+        }
+        previews.sort_unstable_by_key(|(last_used, _, _)| *last_used);
+        let mut evicted = 0;
+        for (_, size, path) in previews {
+            if total_size <= self.max_size {
+                break;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    total_size -= size;
+                    evicted += 1;
+                }
+                // a concurrent request may have just replaced the preview
+                Err(e) => debug!(error = ?e, ?path, "could not evict preview"),
+            }
+        }
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PreviewCache::new(dir.path().join("preview_cache"), 1024).unwrap();
+        assert_eq!(cache.get(42, None).await, None);
+        assert_eq!(cache.usage().unwrap(), PreviewCacheUsage::default());
+        cache.insert(42, b"preview".to_vec()).await;
+        assert_eq!(
+            cache.usage().unwrap(),
+            PreviewCacheUsage {
+                entries: 1,
+                bytes: 7
+            }
+        );
+        assert_eq!(
+            cache.get(42, None).await,
+            Some(LimitedVec(b"preview".to_vec()))
+        );
+        assert_eq!(cache.get(43, None).await, None);
+        // overwriting works as well
+        cache.insert(42, b"newer preview".to_vec()).await;
+        assert_eq!(
+            cache.get(42, None).await,
+            Some(LimitedVec(b"newer preview".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_entries_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PreviewCache::new(dir.path().to_path_buf(), 1024).unwrap();
+        cache.insert(42, b"preview".to_vec()).await;
+        let past = Utc::now() - chrono::Duration::hours(1);
+        assert!(cache.get(42, Some(past)).await.is_some());
+        let future = Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(cache.get(42, Some(future)).await, None);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_previews_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PreviewCache::new(dir.path().to_path_buf(), 250).unwrap();
+        let last_used = |key: u64, minutes_ago: u64| {
+            let time = SystemTime::now() - Duration::from_secs(minutes_ago * 60);
+            std::fs::File::open(cache.path(key))
+                .unwrap()
+                .set_times(FileTimes::new().set_accessed(time))
+                .unwrap();
+        };
+        for key in 0..3 {
+            cache.insert(key, vec![0; 100]).await;
+            last_used(key, 10 - key);
+        }
+        // reading a preview makes it the most recently used one
+        assert!(cache.get(0, None).await.is_some());
+        assert_eq!(cache.evict_least_recently_used().unwrap(), 1);
+        assert!(cache.get(0, None).await.is_some());
+        assert_eq!(cache.get(1, None).await, None);
+        assert!(cache.get(2, None).await.is_some());
+        // within the budget => nothing is evicted
+        assert_eq!(cache.evict_least_recently_used().unwrap(), 0);
+    }
+}
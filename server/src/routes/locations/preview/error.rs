@@ -0,0 +1,210 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use actix_web::http::header::{HeaderValue, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+use super::RenderFailure;
+
+/// Machine-readable reason why no preview could be delivered
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewErrorCode {
+    BadRequest,
+    Forbidden,
+    NotFound,
+    TooManyRequests,
+    InternalServerError,
+    BadGateway,
+    ServiceUnavailable,
+}
+
+/// Why no preview could be delivered
+///
+/// Handlers return it as the error of a [`Result`], it knows which status and body it is answered with
+#[derive(Debug)]
+pub enum PreviewError {
+    /// The id or the query parameters can not be served, e.g. out of bounds dimensions
+    InvalidArgs(String),
+    /// Debugging was requested without a valid `X-Debug-Token`
+    Forbidden(&'static str),
+    NotFound,
+    /// The client rendered too many previews and may only try again after `retry_after`
+    RateLimited {
+        retry_after: Duration,
+    },
+    DbError(sqlx::Error),
+    /// Rendering failed. Only reported when debugging, otherwise the default image is delivered instead
+    TileserverDown(RenderFailure),
+}
+
+impl PreviewError {
+    pub fn code(&self) -> PreviewErrorCode {
+        match self {
+            Self::InvalidArgs(_) => PreviewErrorCode::BadRequest,
+            Self::Forbidden(_) => PreviewErrorCode::Forbidden,
+            Self::NotFound => PreviewErrorCode::NotFound,
+            Self::RateLimited { .. } => PreviewErrorCode::TooManyRequests,
+            // not getting a database connection in time is temporary, unlike other database errors
+            Self::DbError(sqlx::Error::PoolTimedOut) => PreviewErrorCode::ServiceUnavailable,
+            Self::DbError(_) => PreviewErrorCode::InternalServerError,
+            Self::TileserverDown(_) => PreviewErrorCode::BadGateway,
+        }
+    }
+}
+
+impl Display for PreviewError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidArgs(error) => f.write_str(error),
+            Self::Forbidden(error) => f.write_str(error),
+            Self::NotFound => f.write_str("Not found"),
+            Self::RateLimited { .. } => {
+                f.write_str("Too many previews requested, please try again later")
+            }
+            Self::DbError(sqlx::Error::PoolTimedOut) => {
+                f.write_str("The database is overloaded, please try again later")
+            }
+            Self::DbError(_) => {
+                f.write_str("Could not get data for location, please try again later")
+            }
+            Self::TileserverDown(reason) => {
+                write!(f, "could not render the preview: {}", reason.name())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreviewError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::DbError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Error body of the preview endpoints
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+pub struct PreviewErrorBody {
+    /// Human-readable description of what went wrong
+    #[schema(example = "Not found")]
+    error: String,
+    code: PreviewErrorCode,
+}
+
+impl ResponseError for PreviewError {
+    fn status_code(&self) -> StatusCode {
+        match self.code() {
+            PreviewErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            PreviewErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            PreviewErrorCode::NotFound => StatusCode::NOT_FOUND,
+            PreviewErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            PreviewErrorCode::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            PreviewErrorCode::BadGateway => StatusCode::BAD_GATEWAY,
+            PreviewErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Self::RateLimited { retry_after } = self {
+            let seconds = retry_after.as_secs_f64().ceil() as u64;
+            response.insert_header((RETRY_AFTER, HeaderValue::from(seconds)));
+        }
+        response.json(PreviewErrorBody {
+            error: self.to_string(),
+            code: self.code(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn errors_are_json() {
+        let error = PreviewError::InvalidArgs("width=10000 is not allowed".to_string());
+        let resp = error.error_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "width=10000 is not allowed", "code": "bad_request"})
+        );
+    }
+
+    #[test]
+    fn every_error_has_its_status() {
+        let statuses = [
+            (PreviewError::InvalidArgs("invalid".to_string()), 400),
+            (PreviewError::Forbidden("no token"), 403),
+            (PreviewError::NotFound, 404),
+            (
+                PreviewError::RateLimited {
+                    retry_after: Duration::from_secs(1),
+                },
+                429,
+            ),
+            (PreviewError::DbError(sqlx::Error::PoolClosed), 500),
+            (
+                PreviewError::TileserverDown(RenderFailure::TileserverUnreachable),
+                502,
+            ),
+            (PreviewError::DbError(sqlx::Error::PoolTimedOut), 503),
+        ];
+        for (error, status) in statuses {
+            assert_eq!(error.status_code().as_u16(), status, "{error:?}");
+            // the body is what the documentation promises
+            assert_eq!(error.error_response().status(), error.status_code());
+        }
+    }
+
+    #[test]
+    fn errors_keep_their_source() {
+        let error = PreviewError::DbError(sqlx::Error::PoolClosed);
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(source.to_string(), sqlx::Error::PoolClosed.to_string());
+        assert_eq!(
+            PreviewError::TileserverDown(RenderFailure::TimedOut).to_string(),
+            "could not render the preview: timed_out"
+        );
+    }
+
+    #[test]
+    fn rate_limited_clients_learn_when_to_retry() {
+        let error = PreviewError::RateLimited {
+            retry_after: Duration::from_millis(99_500),
+        };
+        let resp = error.error_response();
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "100");
+    }
+
+    #[actix_web::test]
+    async fn handlers_can_return_errors() {
+        async fn handler() -> Result<HttpResponse, PreviewError> {
+            Err(PreviewError::NotFound)
+        }
+        let app = actix_web::test::init_service(
+            actix_web::App::new().route("/", actix_web::web::get().to(handler)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get().uri("/").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "Not found", "code": "not_found"})
+        );
+    }
+}
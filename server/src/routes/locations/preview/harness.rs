@@ -0,0 +1,298 @@
+//! Renders previews end to end, from the request to the decoded pixels
+//!
+//! The location comes from a throwaway postgres, the tiles from a [`MockTileServer`] serving a single color.
+//! Everything the map covers is thus known upfront, which [`assert_uniform`] and [`pixel_checksum`] assert on.
+
+use actix_middleware_etag::Etag;
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderMap, CONTENT_TYPE};
+use actix_web::http::Method;
+use actix_web::middleware::{from_fn, Compress};
+use actix_web::{test, web, App};
+use image::{Rgba, RgbaImage};
+use sqlx::PgPool;
+
+use super::db_tests::load_sample_data;
+use super::{
+    batch_handler, cache_stats_handler, keep_preview_etag, maps_handler, maps_head_handler,
+    meta_handler, random_handler, ready_handler, PreviewConfig,
+};
+use crate::external::download_map_image::TileServer;
+use crate::setup::tests::{MockTileServer, PostgresTestContainer};
+use crate::AppData;
+
+/// What the tiles of [`PreviewHarness::new`] are filled with
+pub(super) const TILE_COLOR: Rgba<u8> = Rgba([210, 105, 30, 255]);
+
+/// The preview endpoints backed by the sample data and tiles of one color
+pub(super) struct PreviewHarness {
+    pg: PostgresTestContainer,
+    pub(super) tileserver: MockTileServer,
+    data: web::Data<AppData>,
+    /// if requests pass the middleware of `main`, which rewrites some responses
+    middleware: bool,
+}
+
+impl PreviewHarness {
+    /// Has to be used from an actix runtime (i.e. `#[actix_web::test]`), like the [`MockTileServer`]
+    pub(super) async fn new(tile_color: Rgba<u8>) -> Self {
+        Self::with_config(tile_color, |_| {}).await
+    }
+
+    /// Like [`Self::new`], with the [`PreviewConfig`] adjusted by `configure`
+    pub(super) async fn with_config(
+        tile_color: Rgba<u8>,
+        configure: impl FnOnce(&mut PreviewConfig),
+    ) -> Self {
+        let tileserver = MockTileServer::serving_solid_tiles(tile_color).await;
+        Self::with_tileserver(tileserver, configure).await
+    }
+
+    /// Like [`Self::with_config`], with the tiles coming from `tileserver`, e.g. one which fails or is slow
+    pub(super) async fn with_tileserver(
+        tileserver: MockTileServer,
+        configure: impl FnOnce(&mut PreviewConfig),
+    ) -> Self {
+        let pg = PostgresTestContainer::new().await;
+        load_sample_data(&pg.pool).await;
+        let mut data = AppData::from(pg.pool.clone());
+        // the attribution is text drawn onto the map => it would be the only part without a known color
+        data.preview.tiles = TileServer::mock(&[&tileserver.url]).with_attribution("");
+        // every request renders => previews of other tests (or an older build) can not get in the way
+        data.preview.cache = None;
+        configure(&mut data.preview);
+        Self {
+            pg,
+            tileserver,
+            data: web::Data::new(data),
+            middleware: false,
+        }
+    }
+
+    /// Queries the database through `pool` instead, e.g. one with fewer connections
+    pub(super) fn with_pool(mut self, pool: PgPool) -> Self {
+        let mut data = AppData::clone(&self.data);
+        data.pool = pool;
+        self.data = web::Data::new(data);
+        self
+    }
+
+    /// Passes the requests through the middleware of `main`, like compression and the etags of every response
+    pub(super) fn with_middleware(mut self) -> Self {
+        self.middleware = true;
+        self
+    }
+
+    /// The database with the sample data
+    pub(super) fn pool(&self) -> &PgPool {
+        &self.pg.pool
+    }
+
+    pub(super) fn data(&self) -> &AppData {
+        &self.data
+    }
+
+    /// Requests `uri` from the preview endpoints
+    pub(super) async fn get(&self, uri: &str) -> Response {
+        self.call(test::TestRequest::get().uri(uri)).await
+    }
+
+    /// Requests the headers of `uri` from the preview endpoints
+    pub(super) async fn head(&self, uri: &str) -> Response {
+        self.call(test::TestRequest::default().method(Method::HEAD).uri(uri))
+            .await
+    }
+
+    /// Sends `req` to the preview endpoints
+    pub(super) async fn call(&self, req: test::TestRequest) -> Response {
+        let app = App::new()
+            .app_data(self.data.clone())
+            .configure(preview_endpoints);
+        if self.middleware {
+            // in the order of main
+            let app = app
+                .wrap(Etag)
+                .wrap(from_fn(keep_preview_etag))
+                .wrap(Compress::default());
+            let app = test::init_service(app).await;
+            Response::read(test::call_service(&app, req.to_request()).await).await
+        } else {
+            let app = test::init_service(app).await;
+            Response::read(test::call_service(&app, req.to_request()).await).await
+        }
+    }
+}
+
+/// Every preview endpoint, like they are registered in main
+fn preview_endpoints(config: &mut web::ServiceConfig) {
+    config
+        .service(maps_handler)
+        .service(maps_head_handler)
+        .service(meta_handler)
+        .service(ready_handler)
+        .service(batch_handler)
+        .service(random_handler)
+        .service(cache_stats_handler);
+}
+
+#[derive(Debug)]
+pub(super) struct Response {
+    pub(super) status: u16,
+    pub(super) content_type: Option<String>,
+    pub(super) headers: HeaderMap,
+    pub(super) body: Vec<u8>,
+}
+
+impl Response {
+    async fn read(resp: ServiceResponse<impl MessageBody>) -> Self {
+        let status = resp.status().as_u16();
+        let headers = resp.headers().clone();
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let body = test::read_body(resp).await.to_vec();
+        Self {
+            status,
+            content_type,
+            headers,
+            body,
+        }
+    }
+
+    /// The decoded preview. Panics if the response is no image
+    pub(super) fn image(&self) -> RgbaImage {
+        assert_eq!(
+            self.status,
+            200,
+            "{:?}",
+            String::from_utf8_lossy(&self.body)
+        );
+        image::load_from_memory(&self.body)
+            .expect("the response should be an image")
+            .into_rgba8()
+    }
+
+    /// The parsed body. Panics if the response is no json
+    pub(super) fn json(&self) -> serde_json::Value {
+        assert_eq!(
+            self.content_type.as_deref(),
+            Some("application/json"),
+            "{:?}",
+            String::from_utf8_lossy(&self.body)
+        );
+        serde_json::from_slice(&self.body).expect("the response should be valid json")
+    }
+}
+
+/// FNV-1a of the dimensions and pixels of `img`
+///
+/// Unlike [`std::hash::DefaultHasher`], it is stable across rust versions => expected checksums can be hardcoded
+pub(super) fn pixel_checksum(img: &RgbaImage) -> u64 {
+    let dimensions = [img.width().to_le_bytes(), img.height().to_le_bytes()];
+    dimensions
+        .iter()
+        .flatten()
+        .chain(img.as_raw())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Asserts that every pixel of the `width`x`height` region at `x`/`y` is `color`, reporting the first one which is not
+#[track_caller]
+pub(super) fn assert_uniform(
+    img: &RgbaImage,
+    (x, y): (u32, u32),
+    (width, height): (u32, u32),
+    color: Rgba<u8>,
+) {
+    let mismatch = (y..y + height)
+        .flat_map(|y| (x..x + width).map(move |x| (x, y)))
+        .map(|(x, y)| (x, y, *img.get_pixel(x, y)))
+        .find(|(_, _, pixel)| *pixel != color);
+    if let Some((x, y, pixel)) = mismatch {
+        panic!("pixel {x}/{y} is {pixel:?} instead of {color:?}");
+    }
+}
+
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{assert_uniform, pixel_checksum};
+
+    #[test]
+    fn checksums_cover_dimensions_and_pixels() {
+        let color = Rgba([210, 105, 30, 255]);
+        let img = RgbaImage::from_pixel(2, 3, color);
+        assert_eq!(pixel_checksum(&img), pixel_checksum(&img.clone()));
+        // same pixels, differently arranged
+        assert_ne!(
+            pixel_checksum(&img),
+            pixel_checksum(&RgbaImage::from_pixel(3, 2, color))
+        );
+        let mut changed = img.clone();
+        changed.put_pixel(1, 2, Rgba([0, 0, 0, 0]));
+        assert_ne!(pixel_checksum(&img), pixel_checksum(&changed));
+        // only the unchanged rows
+        assert_uniform(&changed, (0, 0), (2, 2), color);
+    }
+}
+
+mod db_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::super::{BOTTOM_BAR_HEIGHT, WHITE_PIXEL};
+    use super::*;
+
+    const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+    #[actix_web::test]
+    async fn bare_previews_render_to_the_expected_pixels() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        let resp = harness
+            .get("/api/locations/5121.EG.003/preview?bare=true&pin=false&encoding=png")
+            .await;
+        assert_eq!(resp.content_type.as_deref(), Some("image/png"));
+        let img = resp.image();
+        let map_height = 630 - BOTTOM_BAR_HEIGHT;
+        assert_uniform(&img, (0, 0), (1200, map_height), TILE_COLOR);
+        assert_uniform(
+            &img,
+            (0, map_height),
+            (1200, BOTTOM_BAR_HEIGHT),
+            TRANSPARENT,
+        );
+        // the map above the empty bottom bar. Changes here need a look at the rendered preview
+        assert_eq!(pixel_checksum(&img), 0x574f_5c87_b8eb_8055);
+        assert!(harness.tileserver.requests() > 0);
+    }
+
+    #[actix_web::test]
+    async fn default_cards_render_to_the_expected_pixels() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        let img = harness
+            .get("/api/locations/5121.EG.003/preview?encoding=png")
+            .await
+            .image();
+        assert_eq!((img.width(), img.height()), (1200, 630));
+        // the pinned map above the logo, name and type. Changes here need a look at the rendered preview
+        assert_eq!(pixel_checksum(&img), 0xce9b_2796_7be7_c7eb);
+    }
+
+    #[actix_web::test]
+    async fn the_bottom_bar_is_drawn_below_the_map() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        let img = harness
+            .get("/api/locations/5121.EG.003/preview?pin=false")
+            .await
+            .image();
+        let map_height = 630 - BOTTOM_BAR_HEIGHT;
+        assert_uniform(&img, (0, 0), (1200, map_height), TILE_COLOR);
+        assert_eq!(img.get_pixel(0, 629), &WHITE_PIXEL);
+
+        let resp = harness.get("/api/locations/does-not-exist/preview").await;
+        assert_eq!(resp.status, 404);
+    }
+}
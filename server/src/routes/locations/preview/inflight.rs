@@ -0,0 +1,147 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use super::{EncodedImage, RenderFailure};
+
+type RenderResult = Result<EncodedImage, RenderFailure>;
+type Waiters = HashMap<u64, Vec<oneshot::Sender<RenderResult>>>;
+
+/// Previews which are currently being rendered, with the requests waiting for them
+///
+/// During a viral link, many requests for the same preview arrive before the first one is rendered and cached.
+/// Rendering it only once keeps the load on the tileserver the same as for a single request.
+#[derive(Debug, Clone, Default)]
+pub(super) struct InFlightRenders(Arc<Mutex<Waiters>>);
+
+impl InFlightRenders {
+    /// Runs `render` for the preview identified by `key`, unless it is being rendered already.
+    /// In this case, the result of the ongoing render is shared instead.
+    pub(super) async fn deduplicated(
+        &self,
+        key: u64,
+        render: impl Future<Output = RenderResult>,
+    ) -> RenderResult {
+        let waiting = {
+            let mut waiters = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            match waiters.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    let (sender, receiver) = oneshot::channel();
+                    entry.get_mut().push(sender);
+                    Some(receiver)
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(receiver) = waiting {
+            return match receiver.await {
+                Ok(result) => result,
+                // the ongoing render was cancelled (e.g. by a timeout) => nothing can be shared
+                Err(_) => render.await,
+            };
+        }
+        let leader = Leader {
+            waiters: &self.0,
+            key,
+            finished: false,
+        };
+        let result = render.await;
+        for sender in leader.finish() {
+            // the waiting request might have timed out in the meantime
+            let _ = sender.send(result.clone());
+        }
+        result
+    }
+}
+
+/// Removes the entry of the render once it is finished or cancelled
+struct Leader<'a> {
+    waiters: &'a Mutex<Waiters>,
+    key: u64,
+    finished: bool,
+}
+
+impl Leader<'_> {
+    fn remove(&self) -> Vec<oneshot::Sender<RenderResult>> {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        waiters.remove(&self.key).unwrap_or_default()
+    }
+    fn finish(mut self) -> Vec<oneshot::Sender<RenderResult>> {
+        self.finished = true;
+        self.remove()
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        // dropping the senders wakes up the waiting requests
+        if !self.finished {
+            self.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+
+    use super::super::PreviewEncoding;
+    use super::*;
+    use crate::limited::vec::LimitedVec;
+
+    fn encoded(data: u8) -> RenderResult {
+        Ok(EncodedImage {
+            encoding: PreviewEncoding::Png,
+            data: LimitedVec(vec![data]),
+        })
+    }
+
+    #[tokio::test]
+    async fn concurrent_renders_are_shared() {
+        let renders = InFlightRenders::default();
+        let started = AtomicUsize::new(0);
+        let render = |data| {
+            let started = &started;
+            async move {
+                started.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                encoded(data)
+            }
+        };
+        let results =
+            futures::future::join_all((0..5).map(|i| renders.deduplicated(1, render(i)))).await;
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_eq!(result.unwrap().data.0, vec![0]);
+        }
+        // finished renders are not remembered
+        assert!(renders.0.lock().unwrap().is_empty());
+        let result = renders.deduplicated(1, render(7)).await;
+        assert_eq!(result.unwrap().data.0, vec![7]);
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn cancelled_renders_are_not_awaited() {
+        let renders = InFlightRenders::default();
+        let stuck = renders.deduplicated(1, std::future::pending());
+        let waiting = renders.deduplicated(1, async { encoded(1) });
+        // the first render is cancelled while the second one waits for it
+        let (cancelled, result) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(50), stuck),
+            waiting
+        );
+        assert!(cancelled.is_err());
+        assert_eq!(result.unwrap().data.0, vec![1]);
+        assert!(renders.0.lock().unwrap().is_empty());
+    }
+}
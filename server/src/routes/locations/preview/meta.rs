@@ -0,0 +1,241 @@
+use actix_web::http::header::ACCEPT_LANGUAGE;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Datelike, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::error::{PreviewError, PreviewErrorBody};
+use super::{
+    deserialize_from_str, get_possible_redirect_url, permanent_redirect, with_type_fallback,
+    MapsPathParams,
+};
+use crate::db::location::Location;
+use crate::localisation;
+
+/// What the preview of a location shows
+#[derive(Serialize, Debug, PartialEq, utoipa::ToSchema)]
+struct PreviewMeta {
+    /// Name of the location
+    #[schema(example = "5121.EG.003 (Computerraum)")]
+    name: String,
+    /// Localised name of the type of the location
+    #[schema(example = "Serverraum")]
+    type_common_name: String,
+    /// Latitude of the location
+    #[schema(example = 48.26842603718826)]
+    lat: f64,
+    /// Longitude of the location
+    #[schema(example = 11.677995005953209)]
+    lon: f64,
+    /// Calendar of the location, if it has one
+    #[schema(example = "https://campus.tum.de/tumonline/wbKalender.wbRessource?pResNr=45064")]
+    calendar_url: Option<String>,
+    /// When the calendar was last scraped, if it ever was
+    ///
+    /// ISO 8601, unless `human=true` asks for a localised date
+    #[schema(example = "2024-10-14T13:05:00Z")]
+    last_calendar_scrape_at: Option<String>,
+}
+
+impl PreviewMeta {
+    fn new(location: Location, human: bool, should_use_english: bool) -> Self {
+        // the preview shows the fallback => so does its data
+        let location = with_type_fallback(location, should_use_english);
+        let format = |date| {
+            if human {
+                human_date(date, should_use_english)
+            } else {
+                iso_date(date)
+            }
+        };
+        Self {
+            name: location.name,
+            type_common_name: location.type_common_name,
+            lat: location.lat,
+            lon: location.lon,
+            calendar_url: location.calendar_url,
+            last_calendar_scrape_at: location.last_calendar_scrape_at.map(format),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, utoipa::IntoParams)]
+#[serde(default)]
+struct MetaQueryArgs {
+    #[serde(flatten, default)]
+    lang: localisation::LangQueryArgs,
+    /// Whether dates are localised for humans instead of being ISO 8601. Defaults to `false`.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    human: Option<bool>,
+}
+
+fn iso_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+const GERMAN_MONTHS: [&str; 12] = [
+    "Januar",
+    "Februar",
+    "März",
+    "April",
+    "Mai",
+    "Juni",
+    "Juli",
+    "August",
+    "September",
+    "Oktober",
+    "November",
+    "Dezember",
+];
+
+/// `date` as people reading german or english would write it
+///
+/// chrono only knows english month names => the german ones are our own
+fn human_date(date: DateTime<Utc>, should_use_english: bool) -> String {
+    if should_use_english {
+        return date.format("%B %-d, %Y, %-I:%M %p UTC").to_string();
+    }
+    let month = GERMAN_MONTHS[date.month0() as usize];
+    date.format(&format!("%-d. {month} %Y, %H:%M Uhr UTC"))
+        .to_string()
+}
+
+/// Get the data of an entry-preview
+///
+/// Returns what the preview of the location shows, without having to decode the image.
+/// Aliases and the language are handled like for the preview itself.
+/// Via `human=true`, dates are localised instead of being ISO 8601.
+#[utoipa::path(
+    tags=["locations"],
+    params(MapsPathParams, MetaQueryArgs),
+    responses(
+        (status = 200, description = "**Data shown in the preview**", body = PreviewMeta, content_type = "application/json"),
+        (status = 308, description = "**Permanent Redirect.** The id is an alias of another location"),
+        (status = 400, description = "**Bad Request.** The query parameters are invalid", body = PreviewErrorBody, content_type = "application/json", example = json!({"error": "Query deserialize error: unknown variant `fr`, expected `de` or `en`", "code": "bad_request"})),
+        (status = 404, description = "**Not found.** Make sure that requested item exists", body = PreviewErrorBody, content_type = "application/json", example = json!({"error": "Not found", "code": "not_found"})),
+        (status = 503, description = "**Service Unavailable.** No database connection was available in time, please try again later", body = PreviewErrorBody, content_type = "application/json", example = json!({"error": "The database is overloaded, please try again later", "code": "service_unavailable"})),
+    )
+)]
+#[get("/api/locations/{id}/preview/meta")]
+pub async fn meta_handler(
+    req: HttpRequest,
+    params: web::Path<MapsPathParams>,
+    args: Result<web::Query<MetaQueryArgs>, actix_web::Error>,
+    data: web::Data<crate::AppData>,
+) -> Result<HttpResponse, PreviewError> {
+    let args = args.map_err(|e| PreviewError::InvalidArgs(e.to_string()))?;
+    let id = params.sanitized_id()?;
+    if data.preview.missing.contains(&id) {
+        return Err(PreviewError::NotFound);
+    }
+    let redirect_url = get_possible_redirect_url(
+        &data.pool,
+        &data.preview.redirect_base(&req),
+        &id,
+        "preview/meta",
+        req.query_string(),
+    )
+    .await?;
+    if let Some(redirect_url) = redirect_url {
+        return Ok(permanent_redirect(redirect_url));
+    }
+    let accept_language = req
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|h| h.to_str().ok());
+    let should_use_english = args.lang.should_use_english_or_accept(accept_language);
+    let human = args.human.unwrap_or_default();
+    match Location::fetch_optional_in_any_language(&data.pool, &id, should_use_english).await {
+        Ok(Some(location)) => {
+            Ok(HttpResponse::Ok().json(PreviewMeta::new(location, human, should_use_english)))
+        }
+        Ok(None) => {
+            data.preview.missing.insert(&id);
+            Err(PreviewError::NotFound)
+        }
+        Err(e) => {
+            error!(error = ?e, "Error preparing statement");
+            Err(PreviewError::DbError(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn dates_are_localised_for_humans() {
+        let date = Utc.with_ymd_and_hms(2024, 3, 5, 14, 7, 0).unwrap();
+        assert_eq!(iso_date(date), "2024-03-05T14:07:00Z");
+        let german = human_date(date, false);
+        let english = human_date(date, true);
+        assert_eq!(german, "5. März 2024, 14:07 Uhr UTC");
+        assert_eq!(english, "March 5, 2024, 2:07 PM UTC");
+        assert_ne!(german, english);
+
+        let args = web::Query::<MetaQueryArgs>::from_query("human=true&lang=en").unwrap();
+        assert_eq!(args.human, Some(true));
+        assert!(args.lang.should_use_english());
+        assert_eq!(MetaQueryArgs::default().human, None);
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::super::harness::{PreviewHarness, TILE_COLOR};
+    use super::*;
+
+    #[actix_web::test]
+    async fn meta_matches_the_location() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        let location = Location::fetch_optional(harness.pool(), "5121.EG.003", true)
+            .await
+            .unwrap()
+            .unwrap();
+        let resp = harness
+            .get("/api/locations/5121.EG.003/preview/meta?lang=en")
+            .await;
+        assert_eq!(resp.status, 200);
+        let meta = resp.json();
+        assert_eq!(
+            meta,
+            serde_json::json!({
+                "name": location.name,
+                "type_common_name": location.type_common_name,
+                "lat": location.lat,
+                "lon": location.lon,
+                "calendar_url": location.calendar_url,
+                "last_calendar_scrape_at": null,
+            })
+        );
+        assert_eq!(meta["name"], "5121.EG.003 (Computerraum)");
+
+        let resp = harness
+            .get("/api/locations/does-not-exist/preview/meta")
+            .await;
+        assert_eq!(resp.status, 404);
+    }
+
+    #[actix_web::test]
+    async fn missing_translations_fall_back() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        sqlx::query("DELETE FROM en WHERE key = '5121.EG.003'")
+            .execute(harness.pool())
+            .await
+            .unwrap();
+        let resp = harness
+            .get("/api/locations/5121.EG.003/preview/meta?lang=en")
+            .await;
+        assert_eq!(resp.status, 200);
+        let meta = resp.json();
+        // the german card is better than none
+        assert_eq!(meta["name"], "5121.EG.003 (Computerraum)");
+        assert_eq!(meta["type_common_name"], "Serverraum");
+    }
+}
@@ -0,0 +1,281 @@
+//! Embeds where a preview comes from into the encoded image
+//!
+//! A saved preview can thus be traced back to its location, and the attribution of the map travels with it.
+//! Only png (as `iTXt` chunks) and jpeg (as a comment) are supported, the other encodings are left as they are.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use super::{EncodedImage, PreviewEncoding};
+use crate::limited::vec::LimitedVec;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// The signature and the `IHDR` chunk, which has to stay the first chunk
+const PNG_HEADER_LENGTH: usize = PNG_SIGNATURE.len() + 4 + 4 + 13 + 4;
+const JPEG_START_OF_IMAGE: &[u8] = &[0xFF, 0xD8];
+const JPEG_COMMENT_MARKER: &[u8] = &[0xFF, 0xFE];
+/// `APP0` to `APP15`, e.g. the `JFIF` header which readers expect right after the start of the image
+const JPEG_APPLICATION_MARKERS: std::ops::RangeInclusive<u8> = 0xE0..=0xEF;
+
+/// What is embedded into a preview
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Provenance<'a> {
+    /// Key of the location
+    pub(super) key: &'a str,
+    pub(super) generated_at: DateTime<Utc>,
+    /// Credits of the map data
+    pub(super) attribution: &'a str,
+}
+
+impl Provenance<'_> {
+    /// `(keyword, text)` pairs, with keywords from the png specification where one fits
+    fn entries(&self) -> [(&'static str, String); 4] {
+        [
+            ("Software", "NavigaTUM".to_string()),
+            ("Location", self.key.to_string()),
+            (
+                "Creation Time",
+                self.generated_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+            ("Copyright", self.attribution.to_string()),
+        ]
+    }
+}
+
+/// `image` with the `provenance` embedded, if its encoding supports it
+pub(super) fn embed(image: EncodedImage, provenance: &Provenance) -> EncodedImage {
+    let data = match image.encoding {
+        PreviewEncoding::Png => with_png_text(&image.data.0, provenance),
+        PreviewEncoding::Jpeg { .. } => with_jpeg_comment(&image.data.0, provenance),
+        _ => None,
+    };
+    match data {
+        Some(data) => EncodedImage {
+            data: LimitedVec(data),
+            ..image
+        },
+        None => image,
+    }
+}
+
+/// Inserts an `iTXt` chunk per entry right after the `IHDR` chunk
+///
+/// `iTXt` is used instead of `tEXt`, as only it allows utf-8 (e.g. in the `©` of the attribution)
+fn with_png_text(png: &[u8], provenance: &Provenance) -> Option<Vec<u8>> {
+    if !png.starts_with(PNG_SIGNATURE) || png.len() < PNG_HEADER_LENGTH {
+        return None;
+    }
+    let (header, rest) = png.split_at(PNG_HEADER_LENGTH);
+    let mut out = header.to_vec();
+    for (keyword, text) in provenance.entries() {
+        // keyword, no compression, no language tag and no translated keyword
+        let mut data = Vec::with_capacity(keyword.len() + 5 + text.len());
+        data.extend_from_slice(keyword.as_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 0]);
+        data.extend_from_slice(text.as_bytes());
+        write_png_chunk(&mut out, b"iTXt", &data);
+    }
+    out.extend_from_slice(rest);
+    Some(out)
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let length = u32::try_from(data.len()).expect("the metadata is tiny");
+    out.extend_from_slice(&length.to_be_bytes());
+    let crc_start = out.len();
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[crc_start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-32 (ISO-HDLC), as required for png chunks
+///
+/// The metadata is only a few bytes => the bitwise variant is fast enough
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(u32::MAX, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Inserts a comment segment with all entries right after the application segments
+fn with_jpeg_comment(jpeg: &[u8], provenance: &Provenance) -> Option<Vec<u8>> {
+    if !jpeg.starts_with(JPEG_START_OF_IMAGE) {
+        return None;
+    }
+    let mut header_length = JPEG_START_OF_IMAGE.len();
+    while let [0xFF, marker, length_high, length_low, ..] = jpeg[header_length..] {
+        if !JPEG_APPLICATION_MARKERS.contains(&marker) {
+            break;
+        }
+        // the length includes its own two bytes, but not the marker
+        header_length += 2 + usize::from(u16::from_be_bytes([length_high, length_low]));
+        if header_length > jpeg.len() {
+            return None;
+        }
+    }
+    let (header, rest) = jpeg.split_at(header_length);
+    let comment = provenance
+        .entries()
+        .map(|(keyword, text)| format!("{keyword}: {text}"))
+        .join("\n");
+    // the length includes its own two bytes
+    let length = u16::try_from(comment.len() + 2).ok()?;
+    let mut out = Vec::with_capacity(jpeg.len() + comment.len() + 4);
+    out.extend_from_slice(header);
+    out.extend_from_slice(JPEG_COMMENT_MARKER);
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(comment.as_bytes());
+    out.extend_from_slice(rest);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    use super::super::encode_image;
+    use super::*;
+
+    /// The `(keyword, text)` of every `iTXt` chunk of `png`
+    pub(super) fn png_text_chunks(png: &[u8]) -> Vec<(String, String)> {
+        let mut chunks = Vec::new();
+        let mut rest = &png[PNG_SIGNATURE.len()..];
+        while rest.len() >= 12 {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (chunk_type, data) = (&rest[4..8], &rest[8..8 + length]);
+            if chunk_type == b"iTXt" {
+                let mut fields = data.splitn(2, |byte| *byte == 0);
+                let keyword = String::from_utf8(fields.next().unwrap().to_vec()).unwrap();
+                // compression flag and method, then the empty language tag and translated keyword
+                let text = &fields.next().unwrap()[4..];
+                chunks.push((keyword, String::from_utf8(text.to_vec()).unwrap()));
+            }
+            rest = &rest[12 + length..];
+        }
+        chunks
+    }
+
+    fn provenance() -> Provenance<'static> {
+        Provenance {
+            key: "5121.EG.003",
+            generated_at: Utc.with_ymd_and_hms(2024, 3, 5, 14, 7, 0).unwrap(),
+            attribution: "© OpenStreetMap contributors",
+        }
+    }
+
+    #[test]
+    fn png_text_chunks_are_embedded() {
+        let img = image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 101, 189, 255]));
+        let png = encode_image(&img, PreviewEncoding::Png);
+        let embedded = embed(png.clone(), &provenance());
+        assert_eq!(
+            png_text_chunks(&embedded.data.0),
+            vec![
+                ("Software".to_string(), "NavigaTUM".to_string()),
+                ("Location".to_string(), "5121.EG.003".to_string()),
+                (
+                    "Creation Time".to_string(),
+                    "2024-03-05T14:07:00Z".to_string()
+                ),
+                (
+                    "Copyright".to_string(),
+                    "© OpenStreetMap contributors".to_string()
+                ),
+            ]
+        );
+        // decoders still accept it => the chunks are well-formed
+        let decoded = image::load_from_memory(&embedded.data.0).unwrap();
+        assert_eq!(decoded.into_rgba8(), img);
+        assert!(png_text_chunks(&png.data.0).is_empty());
+    }
+
+    /// The markers and contents of the segments of `jpeg` up to the start of the scan
+    fn jpeg_segments(jpeg: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut segments = Vec::new();
+        let mut rest = jpeg.strip_prefix(JPEG_START_OF_IMAGE).unwrap();
+        while let [0xFF, marker, length_high, length_low, ..] = *rest {
+            let length = usize::from(u16::from_be_bytes([length_high, length_low]));
+            segments.push((marker, &rest[4..2 + length]));
+            rest = &rest[2 + length..];
+            // the entropy-coded data follows without a length
+            if marker == 0xDA {
+                break;
+            }
+        }
+        segments
+    }
+
+    #[test]
+    fn jpeg_comments_are_embedded() {
+        let img = image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 101, 189, 255]));
+        let jpeg = encode_image(&img, PreviewEncoding::Jpeg { quality: 80 });
+        let embedded = embed(jpeg.clone(), &provenance());
+        let segments = jpeg_segments(&embedded.data.0);
+        let markers = segments.iter().map(|(marker, _)| *marker);
+        // the `JFIF` header has to stay the first segment
+        assert_eq!(markers.take(2).collect::<Vec<_>>(), vec![0xE0, 0xFE]);
+        assert!(segments[0].1.starts_with(b"JFIF\0"));
+        let comment = segments[1].1;
+        assert!(comment.starts_with(b"Software: NavigaTUM\nLocation: 5121.EG.003\n"));
+        // only the comment was added
+        assert_eq!(
+            segments.len(),
+            jpeg_segments(&jpeg.data.0).len() + 1,
+            "{segments:?}"
+        );
+        assert_eq!(
+            image::load_from_memory(&embedded.data.0).unwrap().width(),
+            4
+        );
+    }
+
+    #[test]
+    fn other_encodings_are_left_alone() {
+        let img = image::RgbaImage::new(4, 4);
+        let webp = encode_image(&img, PreviewEncoding::WebP);
+        assert_eq!(embed(webp.clone(), &provenance()).data.0, webp.data.0);
+    }
+
+    #[test]
+    fn crc_matches_the_png_specification() {
+        // the `IEND` chunk, which every png ends with
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::super::harness::{PreviewHarness, TILE_COLOR};
+    use super::tests::png_text_chunks;
+
+    const URI: &str = "/api/locations/5121.EG.003/preview?encoding=png";
+
+    #[actix_web::test]
+    async fn rendered_previews_name_their_location() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        let chunks = png_text_chunks(&harness.get(URI).await.body);
+        let location = chunks.iter().find(|(keyword, _)| keyword == "Location");
+        assert_eq!(location.map(|(_, key)| key.as_str()), Some("5121.EG.003"));
+        assert!(chunks.iter().any(|(keyword, _)| keyword == "Creation Time"));
+    }
+
+    #[actix_web::test]
+    async fn metadata_can_be_disabled() {
+        let harness = PreviewHarness::with_config(TILE_COLOR, |config| {
+            config.embed_metadata = false;
+        })
+        .await;
+        let resp = harness.get(URI).await;
+        assert_eq!(resp.status, 200);
+        assert_eq!(png_text_chunks(&resp.body), Vec::new());
+    }
+}
@@ -0,0 +1,133 @@
+//! Metrics of the preview endpoint
+//!
+//! They are registered in the [`prometheus::default_registry`], which is exported via `/api/metrics`
+
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use prometheus::core::Collector;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+
+use super::{PreviewEncoding, RenderFailure};
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "navigatum_api_preview_requests_total",
+        "Requested previews, by image format",
+        &["format"]
+    )
+    .expect("metric can be registered")
+});
+
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "navigatum_api_preview_cache_lookups_total",
+        "Lookups of rendered previews in the preview cache, by image format and if they were a hit or miss",
+        &["format", "outcome"]
+    )
+    .expect("metric can be registered")
+});
+
+static RENDER_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "navigatum_api_preview_render_duration_seconds",
+        "Time needed to render a preview, by image format and if rendering succeeded",
+        &["format", "outcome"],
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 30.0]
+    )
+    .expect("metric can be registered")
+});
+
+static FALLBACKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "navigatum_api_preview_fallbacks_total",
+        "Default images served instead of a rendered preview, by image format",
+        &["format"]
+    )
+    .expect("metric can be registered")
+});
+
+static FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "navigatum_api_preview_render_failures_total",
+        "Previews which could not be rendered, by why rendering failed",
+        &["reason"]
+    )
+    .expect("metric can be registered")
+});
+
+pub(super) fn record_request(encoding: PreviewEncoding) {
+    REQUESTS.with_label_values(&[encoding.name()]).inc();
+}
+
+pub(super) fn record_cache_lookup(encoding: PreviewEncoding, hit: bool) {
+    let outcome = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS
+        .with_label_values(&[encoding.name(), outcome])
+        .inc();
+}
+
+/// How many lookups in the preview cache were hits and misses since startup, over all image formats
+pub(super) fn cache_lookups() -> (u64, u64) {
+    let (mut hits, mut misses) = (0, 0);
+    for family in CACHE_LOOKUPS.collect() {
+        for metric in family.get_metric() {
+            let count = metric.get_counter().get_value() as u64;
+            let is_hit = metric
+                .get_label()
+                .iter()
+                .any(|label| label.get_name() == "outcome" && label.get_value() == "hit");
+            if is_hit {
+                hits += count;
+            } else {
+                misses += count;
+            }
+        }
+    }
+    (hits, misses)
+}
+
+pub(super) fn record_render(encoding: PreviewEncoding, rendered: bool, duration: Duration) {
+    let outcome = if rendered { "rendered" } else { "failed" };
+    RENDER_DURATION
+        .with_label_values(&[encoding.name(), outcome])
+        .observe(duration.as_secs_f64());
+}
+
+pub(super) fn record_fallback(encoding: PreviewEncoding) {
+    FALLBACKS.with_label_values(&[encoding.name()]).inc();
+}
+
+pub(super) fn record_failure(reason: RenderFailure) {
+    FAILURES.with_label_values(&[reason.name()]).inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn renders_are_scraped() {
+        record_render(PreviewEncoding::WebP, true, Duration::from_millis(120));
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::build_metrics())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/api/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let count = body
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix(
+                    r#"navigatum_api_preview_render_duration_seconds_count{format="webp",outcome="rendered"}"#,
+                )
+            })
+            .unwrap_or_else(|| panic!("render histogram is missing in {body}"));
+        assert!(count.trim().parse::<u64>().unwrap() > 0, "{body}");
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::{Arc, Mutex};
+
+use cached::{Cached, TimedSizedCache};
+
+/// How long an id is remembered as missing.
+/// Kept short, so that newly added locations are not hidden for long
+const MISSING_TTL_SECONDS: u64 = 60;
+/// How many missing ids are remembered at most
+const MISSING_CAPACITY: usize = 10_000;
+
+/// Remembers ids for which no location exists
+///
+/// Crawlers probing many non-existent ids would otherwise cause multiple database queries per request
+#[derive(Debug, Clone)]
+pub struct MissingIds(Arc<Mutex<TimedSizedCache<String, ()>>>);
+
+impl Default for MissingIds {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(
+            TimedSizedCache::with_size_and_lifespan(MISSING_CAPACITY, MISSING_TTL_SECONDS),
+        )))
+    }
+}
+
+impl MissingIds {
+    pub fn contains(&self, id: &str) -> bool {
+        let mut cache = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        cache.cache_get(id).is_some()
+    }
+    pub fn insert(&self, id: &str) {
+        let mut cache = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        cache.cache_set(id.to_string(), ());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_ids_are_remembered() {
+        let missing = MissingIds::default();
+        assert!(!missing.contains("does-not-exist"));
+        missing.insert("does-not-exist");
+        assert!(missing.contains("does-not-exist"));
+        assert!(!missing.contains("5121.EG.003"));
+    }
+}
@@ -0,0 +1,96 @@
+use actix_web::http::header::{CacheControl, CacheDirective, LOCATION};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use tracing::error;
+
+use super::error::{PreviewError, PreviewErrorBody};
+use super::redirect_url;
+use crate::db::location::Location;
+
+/// Preview of a random location
+///
+/// Redirects to the preview of a random location, e.g. for screenshots or smoke tests.
+/// The query parameters are passed on unchanged => they are the same as for the preview itself.
+/// Only locations which can be shown on a map are picked.
+#[utoipa::path(
+    tags=["locations"],
+    responses(
+        (status = 307, description = "**Temporary Redirect.** To the preview of a random location"),
+        (status = 404, description = "**Not found.** There are no locations yet, e.g. because the data is still loading", body = PreviewErrorBody, content_type = "application/json", example = json!({"error": "Not found", "code": "not_found"})),
+        (status = 503, description = "**Service Unavailable.** No database connection was available in time, please try again later", body = PreviewErrorBody, content_type = "application/json", example = json!({"error": "The database is overloaded, please try again later", "code": "service_unavailable"})),
+    )
+)]
+#[get("/api/locations/preview/random")]
+pub async fn random_handler(
+    req: HttpRequest,
+    data: web::Data<crate::AppData>,
+) -> Result<HttpResponse, PreviewError> {
+    match Location::fetch_random_key(&data.pool).await {
+        Ok(Some(key)) => {
+            let url = redirect_url(
+                &data.preview.redirect_base(&req),
+                &key,
+                "preview",
+                req.query_string(),
+            );
+            // every request should get a different location
+            Ok(HttpResponse::TemporaryRedirect()
+                .insert_header((LOCATION, url))
+                .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+                .finish())
+        }
+        Ok(None) => Err(PreviewError::NotFound),
+        Err(e) => {
+            error!(error = ?e, "could not pick a random location");
+            Err(PreviewError::DbError(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::super::db_tests::load_sample_data;
+    use super::super::harness::{PreviewHarness, TILE_COLOR};
+    use super::*;
+
+    #[actix_web::test]
+    async fn random_previews_resolve_to_an_image() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        for lang in ["de", "en"] {
+            sqlx::query(&format!("DELETE FROM {lang}"))
+                .execute(harness.pool())
+                .await
+                .unwrap();
+        }
+        let random = "/api/locations/preview/random?lang=en&format=square";
+        // nothing to pick from yet
+        assert_eq!(harness.get(random).await.status, 404);
+
+        load_sample_data(harness.pool()).await;
+        let virtual_room = serde_json::json!({"coords":{"accuracy":"building","lat":48.26842603718826,"lon":11.677995005953209,"source":"inferred"},"id":"5121.virtual","name":"Virtueller Raum","props":{},"type":"virtual_room","type_common_name":"Virtueller Raum"});
+        sqlx::query("INSERT INTO de(key,data) VALUES ($1,$2)")
+            .bind("5121.virtual")
+            .bind(&virtual_room)
+            .execute(harness.pool())
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            let resp = harness.get(random).await;
+            assert_eq!(resp.status, 307);
+            assert_eq!(resp.headers.get("cache-control").unwrap(), "no-store");
+            let location = resp.headers.get(LOCATION).unwrap().to_str().unwrap();
+            // virtual rooms are never picked
+            assert_eq!(
+                location,
+                "/api/locations/5121.EG.003/preview?lang=en&format=square"
+            );
+        }
+
+        let resp = harness
+            .get("/api/locations/5121.EG.003/preview?lang=en&format=square")
+            .await;
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.content_type.as_deref(), Some("image/png"));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// At most this many clients are tracked. Beyond it, the client seen least recently is forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+/// How many checks pass between forgetting the clients whose bucket is full again
+const SWEEP_EVERY: u64 = 256;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// Position in [`Buckets::by_recency`]
+    seen: u64,
+}
+
+/// The buckets of all tracked clients, ordered by when they were last seen
+#[derive(Debug, Default)]
+struct Buckets {
+    by_client: HashMap<String, Bucket>,
+    /// Clients by [`Bucket::seen`], the least recently seen first
+    by_recency: BTreeMap<u64, String>,
+    /// Counts the checks, which is what [`Bucket::seen`] is taken from
+    checks: u64,
+}
+
+impl Buckets {
+    fn forget_least_recently_seen(&mut self) {
+        if let Some((_, client)) = self.by_recency.pop_first() {
+            self.by_client.remove(&client);
+        }
+    }
+}
+
+/// Token-bucket rate limiter for rendering previews, keyed by client
+///
+/// Every client may render `burst` previews at once, after which it has to wait for tokens refilling at `per_second`.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    /// Creates the rate limiter.
+    ///
+    /// Returns [`None`] if limiting is disabled, i.e. if `per_second` is not positive
+    pub fn new(per_second: f64, burst: u32) -> Option<Self> {
+        (per_second > 0.0).then(|| Self {
+            per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Arc::default(),
+        })
+    }
+
+    /// Takes a token from the bucket of `client`
+    ///
+    /// If the bucket is empty, the time after which the next token is available is returned instead
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.checks += 1;
+        let seen = buckets.checks;
+        if seen.is_multiple_of(SWEEP_EVERY) {
+            self.forget_full_buckets(&mut buckets, now);
+        }
+        let bucket = match buckets.by_client.get(client).copied() {
+            Some(bucket) => {
+                buckets.by_recency.remove(&bucket.seen);
+                self.refilled(bucket, now)
+            }
+            None => {
+                if buckets.by_client.len() >= MAX_TRACKED_CLIENTS {
+                    buckets.forget_least_recently_seen();
+                }
+                Bucket {
+                    tokens: self.burst,
+                    refilled_at: now,
+                    seen,
+                }
+            }
+        };
+        let (bucket, result) = if bucket.tokens >= 1.0 {
+            let bucket = Bucket {
+                tokens: bucket.tokens - 1.0,
+                ..bucket
+            };
+            (bucket, Ok(()))
+        } else {
+            let retry_after = Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second);
+            (bucket, Err(retry_after))
+        };
+        buckets.by_recency.insert(seen, client.to_string());
+        buckets
+            .by_client
+            .insert(client.to_string(), Bucket { seen, ..bucket });
+        result
+    }
+
+    /// Forgets the clients whose bucket refilled completely, as forgetting them does not change how they are limited
+    ///
+    /// Buckets are refilled whenever their client is seen => the least recently seen ones are the fullest.
+    /// Only those which are certainly full are forgotten, so this stops at the first client seen too recently.
+    fn forget_full_buckets(&self, buckets: &mut Buckets, now: Instant) {
+        let refill_time = Duration::from_secs_f64(self.burst / self.per_second);
+        while let Some((_, client)) = buckets.by_recency.first_key_value() {
+            let refilled_at = buckets.by_client[client].refilled_at;
+            if now.saturating_duration_since(refilled_at) < refill_time {
+                break;
+            }
+            buckets.forget_least_recently_seen();
+        }
+    }
+
+    fn refilled(&self, bucket: Bucket, now: Instant) -> Bucket {
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        Bucket {
+            tokens: (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.burst),
+            refilled_at: now,
+            ..bucket
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn bursting_past_the_limit_is_rejected() {
+        let limiter = RateLimiter::new(2.0, 3).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("1.2.3.4", start), Ok(()));
+        }
+        assert_eq!(
+            limiter.check_at("1.2.3.4", start),
+            Err(Duration::from_millis(500))
+        );
+        // clients have separate buckets
+        assert_eq!(limiter.check_at("5.6.7.8", start), Ok(()));
+        // staying under the rate is fine
+        for i in 1..=10 {
+            let now = start + Duration::from_millis(500 * i);
+            assert_eq!(limiter.check_at("1.2.3.4", now), Ok(()));
+        }
+        // the bucket only refills up to the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("1.2.3.4", later), Ok(()));
+        }
+        assert!(limiter.check_at("1.2.3.4", later).is_err());
+    }
+
+    #[test]
+    fn at_most_a_fixed_number_of_clients_are_tracked() {
+        let limiter = RateLimiter::new(1.0, 1).unwrap();
+        let start = Instant::now();
+        assert_eq!(limiter.check_at("first", start), Ok(()));
+        for i in 0..MAX_TRACKED_CLIENTS * 2 {
+            let _ = limiter.check_at(&format!("client-{i}"), start);
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_client.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(buckets.by_recency.len(), MAX_TRACKED_CLIENTS);
+        // the least recently seen clients were forgotten
+        assert!(!buckets.by_client.contains_key("first"));
+        assert!(!buckets.by_client.contains_key("client-0"));
+        let last = format!("client-{}", MAX_TRACKED_CLIENTS * 2 - 1);
+        assert!(buckets.by_client.contains_key(&last));
+    }
+
+    #[test]
+    fn full_buckets_are_swept_periodically() {
+        let limiter = RateLimiter::new(1.0, 2).unwrap();
+        let start = Instant::now();
+        for i in 0..SWEEP_EVERY - 1 {
+            let _ = limiter.check_at(&format!("client-{i}"), start);
+        }
+        assert_eq!(
+            limiter.buckets.lock().unwrap().by_client.len() as u64,
+            SWEEP_EVERY - 1
+        );
+        // long after, every earlier bucket refilled => the sweep forgets them
+        let later = start + Duration::from_secs(2);
+        let _ = limiter.check_at("latecomer", later);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(
+            buckets.by_client.keys().collect::<Vec<_>>(),
+            vec!["latecomer"]
+        );
+    }
+
+    #[test]
+    fn limiting_can_be_disabled() {
+        assert!(RateLimiter::new(0.0, 10).is_none());
+        assert!(RateLimiter::new(-1.0, 10).is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use sqlx::Executor;
+use tracing::error;
+
+use super::error::PreviewError;
+
+/// Deadline for the database to answer the readiness check
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum DependencyStatus {
+    Ok,
+    Failed,
+}
+
+impl<T, E> From<&Result<T, E>> for DependencyStatus {
+    fn from(result: &Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Self::Ok,
+            Err(_) => Self::Failed,
+        }
+    }
+}
+
+/// Which of the dependencies of the preview endpoint are usable
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+struct Readiness {
+    database: DependencyStatus,
+    tileserver: DependencyStatus,
+}
+
+/// Preview readiness check
+///
+/// Checks that the database answers and that a tileserver delivers tiles.
+/// Without the tileserver, only default images could be served => traffic should be routed elsewhere.
+#[utoipa::path(
+    tags=["locations"],
+    responses(
+        (status = 200, description = "**Ready.** All dependencies are usable", body = Readiness, content_type = "application/json", example = json!({"database": "ok", "tileserver": "ok"})),
+        (status = 503, description = "**Not ready.** At least one dependency failed", body = Readiness, content_type = "application/json", example = json!({"database": "ok", "tileserver": "failed"})),
+    )
+)]
+#[get("/api/locations/preview/ready")]
+pub async fn ready_handler(data: web::Data<crate::AppData>) -> Result<HttpResponse, PreviewError> {
+    let database = async {
+        match tokio::time::timeout(DATABASE_TIMEOUT, data.pool.execute("SELECT 1")).await {
+            Ok(result) => result.map(|_| ()).map_err(anyhow::Error::from),
+            Err(_) => Err(anyhow::anyhow!("timed out after {DATABASE_TIMEOUT:?}")),
+        }
+    };
+    let tileserver = data.preview.tile_server().check_reachable();
+    let (database, tileserver) = tokio::join!(database, tileserver);
+    if let Err(e) = &database {
+        error!(error = ?e, "database is not ready");
+    }
+    if let Err(e) = &tileserver {
+        error!(error = ?e, "tileserver is not ready");
+    }
+    let readiness = Readiness {
+        database: DependencyStatus::from(&database),
+        tileserver: DependencyStatus::from(&tileserver),
+    };
+    // not being ready is what is asked for, not an error of this endpoint
+    if database.is_ok() && tileserver.is_ok() {
+        Ok(HttpResponse::Ok().json(readiness))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(readiness))
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use pretty_assertions::assert_eq;
+
+    use super::super::harness::{PreviewHarness, TILE_COLOR};
+    use super::*;
+    use crate::setup::tests::MockTileServer;
+
+    async fn readiness(harness: &PreviewHarness) -> (u16, serde_json::Value) {
+        let resp = harness.get("/api/locations/preview/ready").await;
+        (resp.status, resp.json())
+    }
+
+    #[actix_web::test]
+    async fn ready_if_everything_is_reachable() {
+        let harness = PreviewHarness::new(TILE_COLOR).await;
+        let (status, body) = readiness(&harness).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            serde_json::json!({"database": "ok", "tileserver": "ok"})
+        );
+        assert_eq!(harness.tileserver.requests(), 1);
+    }
+
+    #[actix_web::test]
+    async fn not_ready_if_the_tileserver_is_down() {
+        let mock = MockTileServer::new(|_| async { HttpResponse::BadGateway().finish() }).await;
+        let harness = PreviewHarness::with_tileserver(mock, |_| {}).await;
+        let (status, body) = readiness(&harness).await;
+        assert_eq!(status, 503);
+        assert_eq!(
+            body,
+            serde_json::json!({"database": "ok", "tileserver": "failed"})
+        );
+        // outages must not be retried, the orchestrator polls anyway
+        assert_eq!(harness.tileserver.requests(), 1);
+    }
+}
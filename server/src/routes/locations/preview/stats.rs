@@ -0,0 +1,229 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use tracing::error;
+
+use super::cache::{PreviewCache, PreviewCacheUsage};
+use super::error::PreviewError;
+use super::metrics;
+use crate::external::download_map_image::{tile_cache_lookups, TileCache, TileCacheUsage};
+
+/// How long the measured usage of the caches is reused, as measuring walks the whole caches
+const USAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the caches store on disk. [`None`] if a cache is disabled or could not be measured
+#[derive(Debug, Default, Copy, Clone)]
+struct Usage {
+    tiles: Option<TileCacheUsage>,
+    previews: Option<PreviewCacheUsage>,
+}
+
+impl Usage {
+    fn measure(tiles: Option<&TileCache>, previews: Option<&PreviewCache>) -> Self {
+        let tiles = tiles.and_then(|cache| match cache.usage() {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                error!(error = ?e, "could not measure the tile cache");
+                None
+            }
+        });
+        let previews = previews.and_then(|cache| match cache.usage() {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                error!(error = ?e, "could not measure the preview cache");
+                None
+            }
+        });
+        Self { tiles, previews }
+    }
+}
+
+/// The last measured [`Usage`], shared by all workers
+#[derive(Debug, Default, Clone)]
+pub struct MeasuredUsage(Arc<Mutex<Option<(Instant, Usage)>>>);
+
+impl MeasuredUsage {
+    /// The usage of the caches, measured again if the last measurement is older than [`USAGE_REFRESH_INTERVAL`]
+    async fn get(&self, tiles: Option<&TileCache>, previews: Option<&PreviewCache>) -> Usage {
+        let last = *self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((measured_at, usage)) = last {
+            if measured_at.elapsed() < USAGE_REFRESH_INTERVAL {
+                return usage;
+            }
+        }
+        let (tiles, previews) = (tiles.cloned(), previews.cloned());
+        let usage =
+            tokio::task::spawn_blocking(move || Usage::measure(tiles.as_ref(), previews.as_ref()))
+                .await
+                .expect("measuring the caches should not panic");
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), usage));
+        usage
+    }
+}
+
+/// Share of the lookups which were hits. [`None`] if nothing was looked up yet
+fn hit_ratio(hits: u64, misses: u64) -> Option<f64> {
+    let lookups = hits + misses;
+    (lookups > 0).then(|| hits as f64 / lookups as f64)
+}
+
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+struct TileCacheStats {
+    /// Cached tile locations
+    #[schema(example = 1520)]
+    entries: u64,
+    /// Distinct tile contents, as identical tiles (e.g. of water) are stored only once
+    #[schema(example = 830)]
+    blobs: u64,
+    /// Size of the cache on disk
+    #[schema(example = 41_943_040)]
+    bytes: u64,
+    /// Lookups of tiles which were cached, since startup
+    #[schema(example = 9000)]
+    hits: u64,
+    /// Lookups of tiles which had to be downloaded, since startup
+    #[schema(example = 1000)]
+    misses: u64,
+    /// Share of the lookups which were hits, `null` if nothing was looked up yet
+    #[schema(example = 0.9)]
+    hit_ratio: Option<f64>,
+}
+
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+struct PreviewCacheStats {
+    /// Cached previews
+    #[schema(example = 120)]
+    entries: u64,
+    /// Size of the cache on disk
+    #[schema(example = 12_582_912)]
+    bytes: u64,
+    /// Requests for previews which were cached, since startup
+    #[schema(example = 300)]
+    hits: u64,
+    /// Requests for previews which had to be rendered, since startup
+    #[schema(example = 100)]
+    misses: u64,
+    /// Share of the lookups which were hits, `null` if nothing was looked up yet
+    #[schema(example = 0.75)]
+    hit_ratio: Option<f64>,
+}
+
+/// Usage of the caches of the preview endpoint. A cache is `null` if it is disabled
+#[derive(Serialize, Debug, utoipa::ToSchema)]
+struct CacheStats {
+    tiles: Option<TileCacheStats>,
+    previews: Option<PreviewCacheStats>,
+}
+
+/// Cache statistics
+///
+/// How much the caches of map tiles and rendered previews store on disk, and how often they were hit since startup.
+/// Helps to size `TILE_CACHE_MAX_SIZE` and to tune `PREVIEW_MAX_AGE`.
+///
+/// Measuring the usage on disk walks the whole caches => it is only measured again after a minute.
+/// The hits and misses are always up to date.
+#[utoipa::path(
+    tags=["locations"],
+    responses(
+        (status = 200, description = "**Statistics of the caches**", body = CacheStats, content_type = "application/json"),
+    )
+)]
+#[get("/api/locations/preview/cache/stats")]
+pub async fn cache_stats_handler(
+    data: web::Data<crate::AppData>,
+) -> Result<HttpResponse, PreviewError> {
+    let config = &data.preview;
+    let usage = config
+        .cache_usage
+        .get(config.tiles.cache(), config.cache.as_ref())
+        .await;
+    let tiles = usage.tiles.map(|usage| {
+        let (hits, misses) = tile_cache_lookups();
+        TileCacheStats {
+            entries: usage.entries,
+            blobs: usage.blobs,
+            bytes: usage.bytes,
+            hits,
+            misses,
+            hit_ratio: hit_ratio(hits, misses),
+        }
+    });
+    let previews = usage.previews.map(|usage| {
+        let (hits, misses) = metrics::cache_lookups();
+        PreviewCacheStats {
+            entries: usage.entries,
+            bytes: usage.bytes,
+            hits,
+            misses,
+            hit_ratio: hit_ratio(hits, misses),
+        }
+    });
+    Ok(HttpResponse::Ok().json(CacheStats { tiles, previews }))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn hit_ratio_is_undefined_without_lookups() {
+        assert_eq!(hit_ratio(0, 0), None);
+        assert_eq!(hit_ratio(3, 1), Some(0.75));
+        assert_eq!(hit_ratio(0, 5), Some(0.0));
+    }
+
+    #[actix_web::test]
+    async fn usage_is_measured_again_only_after_a_while() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = PreviewCache::new(dir.path().to_path_buf(), 1024 * 1024).unwrap();
+        let measured = MeasuredUsage::default();
+        let entries = |usage: Usage| usage.previews.unwrap().entries;
+        assert_eq!(entries(measured.get(None, Some(&cache)).await), 0);
+        cache.insert(42, b"preview".to_vec()).await;
+        assert_eq!(entries(measured.get(None, Some(&cache)).await), 0);
+        // pretend the last measurement is old
+        let stale = Instant::now() - USAGE_REFRESH_INTERVAL;
+        measured.0.lock().unwrap().as_mut().unwrap().0 = stale;
+        assert_eq!(entries(measured.get(None, Some(&cache)).await), 1);
+    }
+}
+
+#[cfg(test)]
+mod db_tests {
+    use super::super::harness::{PreviewHarness, TILE_COLOR};
+    use super::*;
+
+    #[actix_web::test]
+    async fn renders_show_up_in_the_stats() {
+        let tile_dir = tempfile::tempdir().unwrap();
+        let preview_dir = tempfile::tempdir().unwrap();
+        let harness = PreviewHarness::with_config(TILE_COLOR, |config| {
+            config.tiles = config
+                .tiles
+                .clone()
+                .with_cache(tile_dir.path().to_path_buf());
+            config.cache = PreviewCache::new(preview_dir.path().to_path_buf(), 1024 * 1024);
+        })
+        .await;
+        let resp = harness.get("/api/locations/5121.EG.003/preview").await;
+        assert_eq!(resp.status, 200);
+        assert!(harness.tileserver.requests() > 0);
+
+        let resp = harness.get("/api/locations/preview/cache/stats").await;
+        assert_eq!(resp.status, 200);
+        let stats = resp.json();
+        let tiles = &stats["tiles"];
+        assert!(tiles["entries"].as_u64().unwrap() >= 1, "{stats}");
+        assert!(tiles["blobs"].as_u64().unwrap() >= 1, "{stats}");
+        assert!(tiles["bytes"].as_u64().unwrap() > 0, "{stats}");
+        // other tests run in the same process => only a lower bound is known
+        assert!(tiles["misses"].as_u64().unwrap() >= 1, "{stats}");
+        let previews = &stats["previews"];
+        assert_eq!(previews["entries"], 1, "{stats}");
+        assert!(previews["misses"].as_u64().unwrap() >= 1, "{stats}");
+    }
+}
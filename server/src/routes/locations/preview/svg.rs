@@ -0,0 +1,30 @@
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+
+use super::{encode_image, EncodedImage, PreviewEncoding};
+use crate::limited::vec::LimitedVec;
+use crate::overlays::text::OverlayText;
+
+/// Embeds `img` as png into an svg and writes `texts` on top of it as vector text
+///
+/// The map is a raster anyway, but this way, the text stays sharp when the preview is scaled in an embed.
+/// Our logo only exists as raster image => it stays part of `img`.
+pub(super) fn encode(img: &image::RgbaImage, texts: &[OverlayText]) -> EncodedImage {
+    let raster = encode_image(img, PreviewEncoding::Png);
+    let (width, height) = img.dimensions();
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+    svg.push_str(&format!(
+        r#"<image width="{width}" height="{height}" xlink:href="data:image/png;base64,{}"/>"#,
+        BASE64_STANDARD.encode(&raster.data.0)
+    ));
+    for text in texts {
+        svg.push_str(&text.to_svg((width, height)));
+    }
+    svg.push_str("</svg>");
+    EncodedImage {
+        encoding: PreviewEncoding::Svg,
+        data: LimitedVec(svg.into_bytes()),
+    }
+}
@@ -1,3 +1,8 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
 use meilisearch_sdk::client::Client;
 use testcontainers_modules::testcontainers::{ContainerAsync, ImageExt};
 use testcontainers_modules::{meilisearch, testcontainers::runners::AsyncRunner};
@@ -76,6 +81,89 @@ impl MeiliSearchTestContainer {
     }
 }
 
+/// A local http server standing in for the tileserver
+///
+/// Has to be used from an actix runtime (i.e. `#[actix_web::test]`)
+pub struct MockTileServer {
+    pub url: String,
+    requests: Arc<AtomicUsize>,
+    handle: actix_web::dev::ServerHandle,
+}
+
+impl MockTileServer {
+    /// Starts a server answering the `n`-th request (starting at 0) with `respond(n)`, regardless of the path
+    pub async fn new<F, Fut>(respond: F) -> Self
+    where
+        F: Fn(usize) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = HttpResponse> + 'static,
+    {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = HttpServer::new(move || {
+            let counter = counter.clone();
+            let respond = respond.clone();
+            App::new().default_service(web::to(move || {
+                respond(counter.fetch_add(1, Ordering::SeqCst))
+            }))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        Self {
+            url,
+            requests,
+            handle,
+        }
+    }
+
+    /// Starts a server answering every request with the same valid, colorful 512x512px tile
+    pub async fn serving_tiles() -> Self {
+        let mut tile = image::RgbaImage::new(512, 512);
+        for (x, y, pixel) in tile.enumerate_pixels_mut() {
+            *pixel = image::Rgba([x as u8, y as u8, 128, 255]);
+        }
+        Self::serving(tile).await
+    }
+
+    /// Starts a server answering every request with a 512x512px tile of only `color`
+    ///
+    /// Every map drawn from these tiles is `color` throughout => the expected pixels of a preview are known upfront
+    pub async fn serving_solid_tiles(color: image::Rgba<u8>) -> Self {
+        Self::serving(image::RgbaImage::from_pixel(512, 512, color)).await
+    }
+
+    async fn serving(tile: image::RgbaImage) -> Self {
+        let mut tile_png = Vec::new();
+        tile.write_to(
+            &mut std::io::Cursor::new(&mut tile_png),
+            image::ImageFormat::Png,
+        )
+        .unwrap();
+        Self::new(move |_| {
+            let tile_png = tile_png.clone();
+            async move { HttpResponse::Ok().content_type("image/png").body(tile_png) }
+        })
+        .await
+    }
+
+    /// How many requests the server has received so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockTileServer {
+    fn drop(&mut self) {
+        // the returned future only waits for the shutdown to complete
+        drop(self.handle.stop(false));
+    }
+}
+
 #[tokio::test]
 #[tracing_test::traced_test]
 async fn test_db_setup() {